@@ -0,0 +1,546 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use fsrs::{Card, FSRS, Rating, State as CardState};
+use regex::{Captures, Regex};
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use sqlite::{Connection, State as DBState};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::{ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+use crate::cards::{Cards, ImportReport};
+use crate::{media, notes};
+
+/// A card as Anki stores it. `due` is a day relative to the collection's creation for review cards, and a timestamp for
+/// learning cards.
+struct AnkiCard {
+    kind: i64,
+    due: i64,
+    interval: i64,
+    factor: i64,
+    reps: i64,
+    lapses: i64,
+    data: String
+}
+
+/// The id of the Basic note type in exported decks, which stays the same so that Anki reuses it for every deck exported.
+/// Other note types are numbered after it.
+const NOTE_TYPE_ID: i64 = 1_650_000_000_000;
+
+/// The tables of an Anki collection, as of the last version the current Anki can still import.
+const SCHEMA: &str = "
+    CREATE TABLE col (id INTEGER PRIMARY KEY, crt INTEGER NOT NULL, mod INTEGER NOT NULL, scm INTEGER NOT NULL, ver INTEGER NOT NULL, dty INTEGER NOT NULL, usn INTEGER NOT NULL, ls INTEGER NOT NULL, conf TEXT NOT NULL, models TEXT NOT NULL, decks TEXT NOT NULL, dconf TEXT NOT NULL, tags TEXT NOT NULL);
+    CREATE TABLE notes (id INTEGER PRIMARY KEY, guid TEXT NOT NULL, mid INTEGER NOT NULL, mod INTEGER NOT NULL, usn INTEGER NOT NULL, tags TEXT NOT NULL, flds TEXT NOT NULL, sfld INTEGER NOT NULL, csum INTEGER NOT NULL, flags INTEGER NOT NULL, data TEXT NOT NULL);
+    CREATE TABLE cards (id INTEGER PRIMARY KEY, nid INTEGER NOT NULL, did INTEGER NOT NULL, ord INTEGER NOT NULL, mod INTEGER NOT NULL, usn INTEGER NOT NULL, type INTEGER NOT NULL, queue INTEGER NOT NULL, due INTEGER NOT NULL, ivl INTEGER NOT NULL, factor INTEGER NOT NULL, reps INTEGER NOT NULL, lapses INTEGER NOT NULL, left INTEGER NOT NULL, odue INTEGER NOT NULL, odid INTEGER NOT NULL, flags INTEGER NOT NULL, data TEXT NOT NULL);
+    CREATE TABLE revlog (id INTEGER PRIMARY KEY, cid INTEGER NOT NULL, usn INTEGER NOT NULL, ease INTEGER NOT NULL, ivl INTEGER NOT NULL, lastIvl INTEGER NOT NULL, factor INTEGER NOT NULL, time INTEGER NOT NULL, type INTEGER NOT NULL);
+    CREATE TABLE graves (usn INTEGER NOT NULL, oid INTEGER NOT NULL, type INTEGER NOT NULL);
+    CREATE INDEX ix_notes_usn ON notes (usn);
+    CREATE INDEX ix_cards_usn ON cards (usn);
+    CREATE INDEX ix_revlog_usn ON revlog (usn);
+    CREATE INDEX ix_cards_nid ON cards (nid);
+    CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+    CREATE INDEX ix_revlog_cid ON revlog (cid);
+    CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+/// Imports the notes, cards and media of an Anki package (`.apkg` or `.colpkg`).
+///
+/// Anki decks and note types become decks and note types, with their HTML converted to Markdown. Decks are always created,
+/// numbered if the name is taken, and note types are reused if one with the same fields and templates exists. If `history`
+/// is set, each card's FSRS state is rebuilt by replaying its reviews, otherwise it's approximated from Anki's interval and
+/// ease. Media files are only copied if `media` is set, so that a dry run leaves no trace. The caller runs this inside a
+/// transaction, so that nothing is imported if any part fails.
+pub fn import(connection: &Connection, path: &str, history: bool, media: bool) -> Result<ImportReport, String> {
+    let mut archive = ZipArchive::new(fs::File::open(path).map_err(|error| error.to_string())?).map_err(|error| error.to_string())?;
+    let names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    // Packages from Anki 2.1.50 and later only have a placeholder collection in the old format unless exported for older
+    // versions.
+    if names.contains(&"collection.anki21b".to_string()) && !names.contains(&"collection.anki21".to_string()) {
+        return Err("This package is in a newer format; export it from Anki with \"Support older Anki versions\" checked".to_string());
+    }
+    let collection = ["collection.anki21", "collection.anki2"].into_iter().find(|name| names.contains(&name.to_string())).ok_or("This isn't an Anki package")?;
+
+    let (temp, mut file) = temp_file()?;
+    io::copy(&mut archive.by_name(collection).map_err(|error| error.to_string())?, &mut file).map_err(|error| error.to_string())?;
+    drop(file);
+    let anki = Connection::open(&temp.path).map_err(|error| error.to_string())?;
+    extract_media(&mut archive, media).and_then(|names| import_collection(connection, &anki, history, &names))
+}
+
+/// A collection in the temporary folder, deleted when it's dropped so that it doesn't outlive an import or export that
+/// fails part way. Connections to it are dropped first, as long as they're opened after it.
+struct TempFile { path: PathBuf }
+
+impl Drop for TempFile {
+    fn drop(&mut self) { fs::remove_file(&self.path).ok(); }
+}
+
+/// Creates an empty file for a collection in the temporary folder, with a name no other run uses.
+fn temp_file() -> Result<(TempFile, fs::File), String> {
+    let path = std::env::temp_dir().join(format!("srs-{}-{}.anki2", std::process::id(), Cards::new_guid()));
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(&path).map_err(|error| error.to_string())?;
+
+    Ok((TempFile { path }, file))
+}
+
+fn import_collection(connection: &Connection, anki: &Connection, history: bool, media_names: &HashMap<String, String>) -> Result<ImportReport, String> {
+    let mut statement = anki.prepare("SELECT crt, models, decks FROM col").map_err(|error| error.to_string())?;
+    statement.next().map_err(|error| error.to_string())?;
+    let created = Utc.timestamp_opt(statement.read::<i64, _>("crt").map_err(|error| error.to_string())?, 0).single().ok_or("The collection has an invalid creation time")?;
+    let models: HashMap<String, Value> = serde_json::from_str(&statement.read::<String, _>("models").map_err(|error| error.to_string())?).map_err(|error| error.to_string())?;
+    let anki_decks: HashMap<String, Value> = serde_json::from_str(&statement.read::<String, _>("decks").map_err(|error| error.to_string())?).map_err(|error| error.to_string())?;
+    if models.is_empty() { return Err("This collection is in a newer format; export it from Anki with \"Support older Anki versions\" checked".to_string()); }
+
+    let mut anki_notes: Vec<(i64, i64, String, String)> = Vec::new();
+    let mut statement = anki.prepare("SELECT id, mid, tags, flds FROM notes ORDER BY id").map_err(|error| error.to_string())?;
+    while let Ok(DBState::Row) = statement.next() {
+        anki_notes.push((statement.read::<i64, _>("id").map_err(|error| error.to_string())?, statement.read::<i64, _>("mid").map_err(|error| error.to_string())?, statement.read::<String, _>("tags").map_err(|error| error.to_string())?, statement.read::<String, _>("flds").map_err(|error| error.to_string())?));
+    }
+
+    let mut note_types: HashMap<i64, (i64, usize)> = HashMap::new();
+    let mut decks: HashMap<i64, String> = HashMap::new();
+    let mut imported: Vec<i64> = Vec::new();
+    let mut report = ImportReport::default();
+    for (anki_note, model, tags, fields) in anki_notes {
+        let (note_type, field_count) = match note_types.get(&model) {
+            Some(note_type) => *note_type,
+            None => {
+                let model_json = models.get(&model.to_string()).ok_or(format!("Note {} has no note type", anki_note))?;
+                let note_type = find_note_type(connection, model_json)?;
+                note_types.insert(model, note_type);
+                note_type
+            }
+        };
+        let mut values: Vec<String> = fields.split('\x1f').map(|field| media::rename(&markdown(field), media_names)).collect();
+        values.resize(field_count, String::default());
+        let note = notes::insert_note(connection, note_type, &values);
+        imported.push(note);
+        let rendered = notes::render_cards(connection, note_type, &values);
+        let tags = tags.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+        let mut anki_cards: Vec<(i64, i64, i64, i64, AnkiCard)> = Vec::new();
+        let mut statement = anki.prepare(format!("SELECT * FROM cards WHERE nid = {} ORDER BY ord", anki_note)).map_err(|error| error.to_string())?;
+        while let Ok(DBState::Row) = statement.next() {
+            // Cards in filtered decks keep their home deck and due date in odid and odue.
+            let filtered = statement.read::<i64, _>("odid").map_err(|error| error.to_string())? != 0;
+            anki_cards.push((
+                statement.read::<i64, _>("id").map_err(|error| error.to_string())?,
+                statement.read::<i64, _>(if filtered { "odid" } else { "did" }).map_err(|error| error.to_string())?,
+                statement.read::<i64, _>("ord").map_err(|error| error.to_string())?,
+                statement.read::<i64, _>("queue").map_err(|error| error.to_string())?,
+                AnkiCard {
+                    kind: statement.read::<i64, _>("type").map_err(|error| error.to_string())?,
+                    due: statement.read::<i64, _>(if filtered { "odue" } else { "due" }).map_err(|error| error.to_string())?,
+                    interval: statement.read::<i64, _>("ivl").map_err(|error| error.to_string())?,
+                    factor: statement.read::<i64, _>("factor").map_err(|error| error.to_string())?,
+                    reps: statement.read::<i64, _>("reps").map_err(|error| error.to_string())?,
+                    lapses: statement.read::<i64, _>("lapses").map_err(|error| error.to_string())?,
+                    data: statement.read::<String, _>("data").unwrap_or_default()
+                }
+            ));
+        }
+
+        for (anki_card, anki_deck, template, queue, card) in anki_cards {
+            let Some((_, obverse, reverse)) = rendered.iter().find(|rendered| rendered.0 == template) else { continue; };
+            let deck = match decks.get(&anki_deck) {
+                Some(deck) => deck.clone(),
+                None => {
+                    let anki_deck_json = anki_decks.get(&anki_deck.to_string());
+                    let deck = Cards::create_deck(connection, anki_deck_json.and_then(|deck| deck["name"].as_str()).unwrap_or("Default"))?;
+                    Cards::write_deck_metadata(connection, &deck, &json!({"description": markdown(anki_deck_json.and_then(|deck| deck["desc"].as_str()).unwrap_or_default())}));
+                    decks.insert(anki_deck, deck.clone());
+                    deck
+                }
+            };
+            let reviews = reviews(anki, anki_card)?;
+            let id = Cards::insert_card(connection, &deck, obverse.clone(), reverse.clone(), note, template, false);
+            report.added += 1;
+            let (state, replayed) = schedule(&card, &reviews, created, history);
+            Cards::write_card(connection, &deck, id, &state);
+            let guid = Cards::guid(connection, &deck, id);
+            for (review, duration) in replayed { crate::reviews::log(connection, &guid, &review, Some(duration)); }
+            // Anki's card ids are the time the card was added, in milliseconds.
+            connection.execute(format!("UPDATE \"{}\" SET TAGS = '{}', SUSPENDED = {}, FIRST_STUDY = {}, ADDED = '{}' WHERE ROWID = {}",
+                deck.replace(r#"""#, r#""""#),
+                tags.replace('\'', "''"),
+                (queue == -1) as i64,
+                reviews.first().map(|review| format!("'{}'", review.0.to_rfc3339())).unwrap_or("NULL".to_string()),
+                Utc.timestamp_millis_opt(anki_card).single().unwrap_or(Utc::now()).to_rfc3339(),
+                id
+            )).unwrap();
+        }
+    }
+    if decks.values().any(|deck| Cards::get_deck_options_with(connection, deck)["reverse"] == true) {
+        for note in imported { notes::sync_note(connection, note, None); }
+    }
+    notes::clean(connection);
+
+    Ok(report)
+}
+
+/// Finds a note type with the same fields and templates as an Anki note type, or creates one.
+/// Returns the note type along with its number of fields.
+fn find_note_type(connection: &Connection, model: &Value) -> Result<(i64, usize), String> {
+    let name = model["name"].as_str().unwrap_or("Anki");
+    let fields: Vec<String> = model["flds"].as_array().ok_or(format!("The note type {} has no fields", name))?.iter()
+        .map(|field| field["name"].as_str().map(str::to_string).ok_or(format!("A field of the note type {} has no name", name)))
+        .collect::<Result<Vec<String>, String>>()?;
+    let templates: Vec<(String, String, String)> = model["tmpls"].as_array().ok_or(format!("The note type {} has no templates", name))?.iter()
+        .map(|template| match (template["name"].as_str(), template["qfmt"].as_str(), template["afmt"].as_str()) {
+            (Some(template_name), Some(question), Some(answer)) => Ok((template_name.to_string(), convert_template(question), convert_template(answer))),
+            _ => Err(format!("A template of the note type {} is incomplete", name))
+        })
+        .collect::<Result<Vec<(String, String, String)>, String>>()?;
+    let field_count = fields.len();
+
+    Ok((notes::find_note_type(connection, name.to_string(), fields, templates, model["type"] == 1)?, field_count))
+}
+
+/// Converts an Anki template to Markdown. srs always shows the obverse above the reverse, so a leading `{{FrontSide}}` is
+/// dropped from the answer, and so are filters other than `cloze:`, along with fields for typing in the answer.
+fn convert_template(template: &str) -> String {
+    let template = Regex::new(r"(?is)^\s*\{\{FrontSide\}\}\s*(<hr id=.?answer.?>)?").unwrap().replace(template, "");
+    let template = Regex::new(r"\{\{([^#^/}][^}]*)\}\}").unwrap().replace_all(&template, |captures: &Captures| {
+        let parts: Vec<&str> = captures[1].split(':').map(str::trim).collect();
+        let (field, filters) = parts.split_last().unwrap();
+        if filters.contains(&"type") { String::default() }
+        else if filters.contains(&"cloze") { format!("{{{{cloze:{}}}}}", field) }
+        else { format!("{{{{{}}}}}", field) }
+    });
+
+    markdown(&template)
+}
+
+/// Converts the HTML Anki stores fields and templates in to Markdown, keeping line breaks, bold, italics, images, sounds
+/// and ruby.
+pub fn markdown(html: &str) -> String {
+    // Markdown image paths can't have spaces in them. Sounds are written as images, which cards play.
+    let mut text = Regex::new(r#"(?i)<img\s[^>]*src\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))[^>]*>|\[sound:([^\]]+)\]"#).unwrap().replace_all(&html.replace('\r', ""), |captures: &Captures| {
+        format!("![]({})", captures.iter().skip(1).flatten().next().map(|path| path.as_str()).unwrap_or_default().replace(' ', "%20"))
+    }).to_string();
+    for (pattern, replacement) in [
+        (r"(?is)<(style|script)[^>]*>.*?</(style|script)>", ""),
+        (r"[ \t]*\n[ \t]*", " "),
+        (r"(?i)<br\s*/?>", "\n"),
+        (r"(?i)<div(\s[^>]*)?>", "\n"),
+        (r"(?i)<p(\s[^>]*)?>", "\n\n"),
+        (r"(?i)</(div|p)>", ""),
+        (r"(?i)</?(b|strong)(\s[^>]*)?>", "**"),
+        (r"(?i)</?(i|em)(\s[^>]*)?>", "*"),
+        (r"(?is)<rp>.*?</rp>", ""),
+        (r"(?is)<ruby>(.*?)<rt>(.*?)</rt>\s*</ruby>", "{$1|$2}"),
+        (r"<[^>]*>", ""),
+        (r"[ \t]*\n[ \t]*", "\n"),
+        (r"\n{3,}", "\n\n")
+    ] { text = Regex::new(pattern).unwrap().replace_all(&text, replacement).to_string(); }
+
+    Regex::new(r"&(#[xX][0-9a-fA-F]+|#[0-9]+|[a-zA-Z]+);").unwrap().replace_all(&text, |captures: &Captures| match &captures[1] {
+        "nbsp" => " ".to_string(),
+        "lt" => "<".to_string(),
+        "gt" => ">".to_string(),
+        "amp" => "&".to_string(),
+        "quot" => "\"".to_string(),
+        "apos" => "'".to_string(),
+        entity if entity.starts_with("#x") || entity.starts_with("#X") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32).map(String::from).unwrap_or(captures[0].to_string()),
+        entity if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then(char::from_u32).map(String::from).unwrap_or(captures[0].to_string()),
+        _ => captures[0].to_string()
+    }).trim().to_string()
+}
+
+/// A review of an Anki card, as (time, rating, milliseconds taken). A rating of `None` is a reset to new.
+type Review = (DateTime<Utc>, Option<Rating>, i64);
+
+/// The reviews of an Anki card in order.
+fn reviews(anki: &Connection, card: i64) -> Result<Vec<Review>, String> {
+    let mut reviews: Vec<Review> = Vec::new();
+    let mut statement = anki.prepare(format!("SELECT id, ease, ivl, type, time FROM revlog WHERE cid = {} ORDER BY id", card)).map_err(|error| error.to_string())?;
+    while let Ok(DBState::Row) = statement.next() {
+        let time = Utc.timestamp_millis_opt(statement.read::<i64, _>("id").map_err(|error| error.to_string())?).single().ok_or("A review has an invalid time")?;
+        let duration = statement.read::<i64, _>("time").map_err(|error| error.to_string())?;
+        match (statement.read::<i64, _>("ease").map_err(|error| error.to_string())?, statement.read::<i64, _>("type").map_err(|error| error.to_string())?, statement.read::<i64, _>("ivl").map_err(|error| error.to_string())?) {
+            (1, _, _) => reviews.push((time, Some(Rating::Again), duration)),
+            (2, _, _) => reviews.push((time, Some(Rating::Hard), duration)),
+            (3, _, _) => reviews.push((time, Some(Rating::Good), duration)),
+            (4, _, _) => reviews.push((time, Some(Rating::Easy), duration)),
+            // Manual entries with no interval are cards being forgotten, while other manual entries are rescheduling.
+            (0, 4, 0) => reviews.push((time, None, duration)),
+            _ => ()
+        }
+    }
+
+    Ok(reviews)
+}
+
+/// The FSRS state of an Anki card, replayed from its reviews if `history` is set, or approximated otherwise. Replayed
+/// cards also return the state after each review, with how long it took, so the reviews can be logged.
+fn schedule(card: &AnkiCard, reviews: &[Review], created: DateTime<Utc>, history: bool) -> (Card, Vec<(Card, i64)>) {
+    if card.kind == 0 && !history { return (Card::new(), Vec::new()); }
+    if history {
+        let mut state = Card::new();
+        let mut replayed: Vec<(Card, i64)> = Vec::new();
+        for (time, rating, duration) in reviews {
+            state = match rating {
+                Some(rating) => FSRS::default().schedule(state, *time).select_card(*rating),
+                None => Card::new()
+            };
+            if rating.is_some() { replayed.push((state.clone(), *duration)); }
+        }
+        if card.kind == 0 { return (Card::new(), replayed); }
+        if state.state != CardState::New { return (state, replayed); }
+    }
+
+    // Anki stores FSRS memory state as s and d when FSRS is enabled there, and otherwise an interval and ease, where an
+    // ease of 250% is taken as an average difficulty and 130%, the minimum, as the hardest.
+    let memory: Value = serde_json::from_str(&card.data).unwrap_or_default();
+    let factor = if card.factor == 0 { 2500 } else { card.factor };
+    let state = match card.kind {
+        1 => CardState::Learning,
+        2 => CardState::Review,
+        _ => CardState::Relearning
+    };
+    let due = if card.due > 1_000_000_000 { Utc.timestamp_opt(card.due, 0).single().unwrap_or(created) } else { created + Duration::days(card.due) };
+    (Card {
+        due,
+        stability: memory["s"].as_f64().unwrap_or(card.interval.max(1) as f64) as f32,
+        difficulty: memory["d"].as_f64().unwrap_or((5.0 + (2500 - factor) as f64 / 240.0).clamp(1.0, 10.0)) as f32,
+        elapsed_days: 0,
+        scheduled_days: card.interval.max(0),
+        reps: card.reps as i32,
+        lapses: card.lapses as i32,
+        state,
+        last_review: reviews.last().map(|review| review.0).unwrap_or(due - Duration::days(card.interval.max(0))),
+        previous_state: state,
+        log: None
+    }, Vec::new())
+}
+
+/// Adds the media in a package to the media folder, returning the name each file was stored under by its name in Anki. If
+/// `store` isn't set, the names are only worked out.
+fn extract_media(archive: &mut ZipArchive<fs::File>, store: bool) -> Result<HashMap<String, String>, String> {
+    let mut content = String::default();
+    match archive.by_name("media") {
+        Ok(mut file) => { file.read_to_string(&mut content).map_err(|error| error.to_string())?; }
+        Err(_) => return Ok(HashMap::new())
+    }
+    let media: HashMap<String, String> = serde_json::from_str(&content).map_err(|error| error.to_string())?;
+    let mut names: HashMap<String, String> = HashMap::new();
+    for (entry, name) in media {
+        let mut content: Vec<u8> = Vec::new();
+        archive.by_name(&entry).map_err(|error| error.to_string())?.read_to_end(&mut content).map_err(|error| error.to_string())?;
+        let extension = Path::new(&name).extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_string();
+        let stored = if store { media::store(&content, &extension)? } else { media::name(&content, &extension) };
+        names.insert(name, stored);
+    }
+
+    Ok(names)
+}
+
+/// Exports a deck as an Anki package, with a note for each of its notes and the media its cards use. Each note type the deck
+/// uses becomes an Anki note type with the same fields and templates, so each card is exported as the card of its template,
+/// or of its cloze number for cloze notes. Note types with reversed cards get a template for each of their templates with
+/// the sides swapped.
+///
+/// If `scheduling` is set, cards keep their progress, with their FSRS state stored for Anki's FSRS and their difficulty
+/// approximated as an ease for its older scheduler. Otherwise, every card is new.
+pub fn export(deck: &str, path: &str, scheduling: bool) -> Result<(), String> {
+    let connection = Connection::open(Cards::get_path()).map_err(|error| error.to_string())?;
+    let cards = Cards::get_deck(deck.to_string());
+    let mut note_ids: Vec<i64> = Vec::new();
+    let mut note_cards: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (position, card) in cards.iter().enumerate() {
+        let note = card.5["note"].as_i64().unwrap();
+        if !note_cards.contains_key(&note) { note_ids.push(note); }
+        note_cards.entry(note).or_default().push(position);
+    }
+    let mut note_types: HashMap<i64, notes::NoteType> = HashMap::new();
+    let mut reversed: HashSet<i64> = HashSet::new();
+    let mut note_values: HashMap<i64, (i64, Vec<String>)> = HashMap::new();
+    for note in &note_ids {
+        let (note_type, values) = notes::read_note(&connection, *note);
+        note_types.entry(note_type).or_insert_with(|| notes::get_note_type(&connection, note_type));
+        if note_cards[note].iter().any(|position| cards[*position].5["reversed"] == true) { reversed.insert(note_type); }
+        note_values.insert(*note, (note_type, values));
+    }
+    // A collection needs a note type even if it has no notes.
+    if note_types.is_empty() { note_types.insert(notes::BASIC, notes::get_note_type(&connection, notes::BASIC)); }
+
+    let (temp, file) = temp_file()?;
+    drop(file);
+    let anki = Connection::open(&temp.path).map_err(|error| error.to_string())?;
+    anki.execute(SCHEMA).map_err(|error| error.to_string())?;
+
+    let now = Utc::now().timestamp();
+    let created = Cards::day_start();
+    let deck_id = Utc::now().timestamp_millis();
+    let models: serde_json::Map<String, Value> = note_types.values()
+        .map(|note_type| (model_id(note_type.0).to_string(), anki_model(note_type, reversed.contains(&note_type.0), deck_id, now)))
+        .collect();
+    anki.execute(format!("INSERT INTO col VALUES (1, {}, {}, {}, 11, 0, 0, 0, '{}', '{}', '{}', '{}', '{{}}')",
+        created.timestamp(),
+        now,
+        now * 1000,
+        json!({"nextPos": cards.len() + 1, "estTimes": true, "activeDecks": [1], "sortType": "noteFld", "timeLim": 0, "sortBackwards": false, "addToCur": true, "curDeck": 1, "newSpread": 0, "dueCounts": true, "curModel": note_types.keys().min().copied().map(model_id), "collapseTime": 1200}).to_string().replace('\'', "''"),
+        Value::Object(models).to_string().replace('\'', "''"),
+        json!({"1": anki_deck(1, "Default", "", now), deck_id.to_string(): anki_deck(deck_id, deck, &html(Cards::get_deck_metadata(deck.to_string())["description"].as_str().unwrap()), now)}).to_string().replace('\'', "''"),
+        json!({"1": {
+            "id": 1, "name": "Default", "mod": 0, "usn": 0, "maxTaken": 60, "autoplay": true, "timer": 0, "replayq": true, "dyn": false,
+            "new": {"delays": [1, 10], "ints": [1, 4, 0], "initialFactor": 2500, "order": 1, "perDay": 20, "bury": false},
+            "rev": {"perDay": 200, "ease4": 1.3, "ivlFct": 1, "maxIvl": 36500, "bury": false, "hardFactor": 1.2},
+            "lapse": {"delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 1}
+        }}).to_string().replace('\'', "''")
+    )).map_err(|error| error.to_string())?;
+
+    let tags = Regex::new(r"<[^>]*>").unwrap();
+    let mut media: Vec<String> = Vec::new();
+    for (index, note) in note_ids.iter().enumerate() {
+        let (note_type, values) = &note_values[note];
+        let template_count = note_types[note_type].3.len() as i64;
+        let positions = &note_cards[note];
+        let fields: Vec<String> = values.iter().map(|value| html(value)).collect();
+        let sort_field = tags.replace_all(fields.first().map(String::as_str).unwrap_or_default(), "").to_string();
+        // The note takes the GUID of its first card that isn't reversed, which is the card Anki would make first.
+        let first = positions.iter().min_by_key(|position| (cards[**position].5["reversed"] == true, cards[**position].5["template"].as_i64().unwrap())).unwrap();
+        let mut note_tags: Vec<&str> = Vec::new();
+        for tag in positions.iter().flat_map(|position| cards[*position].5["tags"].as_array().unwrap()).filter_map(Value::as_str) {
+            if !note_tags.contains(&tag) { note_tags.push(tag); }
+        }
+        let anki_note = deck_id + index as i64;
+        anki.execute(format!("INSERT INTO notes VALUES ({}, '{}', {}, {}, -1, '{}', '{}', '{}', {}, 0, '')",
+            anki_note,
+            cards[*first].5["guid"].as_str().unwrap().replace('\'', "''"),
+            model_id(*note_type),
+            now,
+            format!(" {} ", note_tags.join(" ")).replace('\'', "''"),
+            fields.join("\x1f").replace('\'', "''"),
+            sort_field.replace('\'', "''"),
+            i64::from_str_radix(&format!("{:x}", Sha1::digest(&sort_field))[..8], 16).unwrap()
+        )).map_err(|error| error.to_string())?;
+
+        for position in positions {
+            let (_, _, obverse, reverse, state, properties) = &cards[*position];
+            for name in media::references(obverse).into_iter().chain(media::references(reverse)) { if !media.contains(&name) { media.push(name); } }
+            let template = properties["template"].as_i64().unwrap();
+            let (kind, queue, due, interval, factor, left, data) = match scheduling {
+                true => anki_schedule(state, created, *position as i64),
+                false => (0, 0, *position as i64, 0, 0, 0, String::default())
+            };
+            anki.execute(format!("INSERT INTO cards VALUES ({}, {}, {}, {}, {}, -1, {}, {}, {}, {}, {}, {}, {}, {}, 0, 0, 0, '{}')",
+                deck_id + *position as i64,
+                anki_note,
+                deck_id,
+                if properties["reversed"] == true { template_count + template } else { template },
+                now,
+                kind,
+                if properties["suspended"] == true { -1 } else { queue },
+                due,
+                interval,
+                factor,
+                if scheduling { state["reps"].as_i64().unwrap() } else { 0 },
+                if scheduling { state["lapses"].as_i64().unwrap() } else { 0 },
+                left,
+                data
+            )).map_err(|error| error.to_string())?;
+        }
+    }
+    drop(anki);
+
+    let mut zip = ZipWriter::new(fs::File::create(path).map_err(|error| error.to_string())?);
+    zip.start_file("collection.anki2", FileOptions::default()).map_err(|error| error.to_string())?;
+    zip.write_all(&fs::read(&temp.path).map_err(|error| error.to_string())?).map_err(|error| error.to_string())?;
+    let mut names = serde_json::Map::new();
+    for (entry, name) in media.iter().filter(|name| media::media_dir().join(name).is_file()).enumerate() {
+        zip.start_file(entry.to_string(), FileOptions::default()).map_err(|error| error.to_string())?;
+        zip.write_all(&fs::read(media::media_dir().join(name)).map_err(|error| error.to_string())?).map_err(|error| error.to_string())?;
+        names.insert(entry.to_string(), json!(name));
+    }
+    zip.start_file("media", FileOptions::default()).map_err(|error| error.to_string())?;
+    zip.write_all(Value::Object(names).to_string().as_bytes()).map_err(|error| error.to_string())?;
+    zip.finish().map_err(|error| error.to_string())?;
+
+    Ok(())
+}
+
+/// The id of the Anki note type an srs note type is exported as.
+fn model_id(note_type: i64) -> i64 { NOTE_TYPE_ID + note_type - notes::BASIC }
+
+/// An srs note type as an Anki note type. If `reversed` is set and the note type isn't a cloze type, each template gets a
+/// reversed one after the others, with its sides swapped.
+fn anki_model(note_type: &notes::NoteType, reversed: bool, deck_id: i64, now: i64) -> Value {
+    let (id, name, fields, templates, cloze) = note_type;
+    // srs shows the answer on its own, while Anki shows it below the question unless the template places it.
+    let answer = |template: &str| match template.contains("{{FrontSide}}") {
+        true => template.to_string(),
+        false => format!("{{{{FrontSide}}}}\n\n<hr id=answer>\n\n{}", template)
+    };
+    let mut sides: Vec<(String, String, String)> = templates.iter().take(if *cloze { 1 } else { templates.len() })
+        .map(|(name, obverse, reverse)| (name.clone(), html(obverse), answer(&html(reverse))))
+        .collect();
+    if reversed && !cloze {
+        sides.extend(templates.iter().map(|(name, obverse, reverse)| (format!("{} (reversed)", name), html(&reverse.replace("{{FrontSide}}", "")), answer(&html(obverse)))));
+    }
+
+    json!({
+        "id": model_id(*id), "name": format!("srs {}", name), "type": *cloze as i64, "mod": now, "usn": -1, "sortf": 0, "did": deck_id,
+        "tmpls": sides.iter().enumerate().map(|(ord, (name, question, answer))| json!({"name": name, "ord": ord, "qfmt": question, "afmt": answer, "did": null, "bqfmt": "", "bafmt": ""})).collect::<Vec<Value>>(),
+        "flds": fields.iter().enumerate().map(|(ord, name)| json!({"name": name, "ord": ord, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": []})).collect::<Vec<Value>>(),
+        "css": ".card {\n    font-family: arial;\n    font-size: 20px;\n    text-align: center;\n    color: black;\n    background-color: white;\n}\n",
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}", "latexsvg": false, "tags": [], "vers": [],
+        // Older versions of Anki only make the cards whose question uses one of these fields.
+        "req": sides.iter().enumerate().map(|(ord, (_, question, _))| {
+            let used: Vec<usize> = fields.iter().enumerate().filter(|(_, field)| question.contains(&format!("{}}}}}", field))).map(|(index, _)| index).collect();
+            json!([ord, "any", used])
+        }).collect::<Vec<Value>>()
+    })
+}
+
+fn anki_deck(id: i64, name: &str, description: &str, now: i64) -> Value {
+    json!({
+        "id": id, "name": name, "mod": now, "usn": -1, "desc": description, "dyn": 0, "conf": 1, "collapsed": false, "browserCollapsed": false,
+        "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "timeToday": [0, 0], "extendNew": 10, "extendRev": 50
+    })
+}
+
+/// A card's FSRS state as Anki's (type, queue, due, interval, factor, left, data), the reverse of `schedule`.
+fn anki_schedule(state: &Value, created: DateTime<Utc>, position: i64) -> (i64, i64, i64, i64, i64, i64, String) {
+    let due = DateTime::<Utc>::from(DateTime::parse_from_rfc3339(state["due"].as_str().unwrap()).unwrap());
+    let factor = (2500.0 - (state["difficulty"].as_f64().unwrap() - 5.0) * 240.0).clamp(1300.0, 5000.0) as i64;
+    let interval = state["scheduled_days"].as_i64().unwrap().max(1);
+    let data = json!({"s": state["stability"], "d": state["difficulty"]}).to_string();
+    match state["state"].as_i64().unwrap() {
+        2 => (1, 1, due.timestamp(), 0, factor, 1001, data),
+        3 => (2, 2, (due - created).num_days().max(0), interval, factor, 0, data),
+        4 => (3, 1, due.timestamp(), interval, factor, 1001, data),
+        _ => (0, 0, position, 0, 0, 0, String::default())
+    }
+}
+
+/// Converts the Markdown srs cards are written in to HTML for Anki, the reverse of `markdown`, so sounds written as
+/// images are turned back into `[sound:name]`.
+pub fn html(markdown: &str) -> String {
+    let mut text = Regex::new(r"!\[[^\]]*\]\(([^)\s]+)\)").unwrap().replace_all(&markdown.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"), |captures: &Captures| {
+        let name = captures[1].replace("%20", " ");
+        if media::is_sound(&name) { format!("[sound:{}]", name) } else { format!("<img src=\"{}\">", name.replace('"', "&quot;")) }
+    }).to_string();
+    for (pattern, replacement) in [
+        (r"\*\*(.+?)\*\*", "<b>$1</b>"),
+        (r"\*(.+?)\*", "<i>$1</i>"),
+        (r"\{([^{}|]+)\|([^{}]+)\}", "<ruby>$1<rt>$2</rt></ruby>"),
+        (r"\n", "<br>")
+    ] { text = Regex::new(pattern).unwrap().replace_all(&text, replacement).to_string(); }
+
+    text
+}
@@ -9,23 +9,143 @@ This Source Code Form is "Incompatible With Secondary Licenses", as
 defined by the Mozilla Public License, v. 2.0.
 */
 
-use chrono::{DateTime, Utc};
-use dirs;
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
 use fsrs::{Card, Rating, State as CardState, ReviewLog, to_json};
 use sqlite::{Connection, State as DBState, Statement};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::path::Path;
 use std::sync::Mutex;
-use crate::settings;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use crate::{anki, deck_file, delimited, notes, package, reviews, search, settings, text, update};
 
-pub struct Cards { pub cards: Mutex<Vec<(String, i64, String, String, Card)>> }
+/// Every column of a deck table, along with its type. Columns added after the initial release need a default so that
+/// `migrate` can add them to existing decks.
+pub const COLUMNS: [(&str, &str); 26] = [
+    ("OBVERSE", "TEXT"),
+    ("REVERSE", "TEXT"),
+    ("DUE", "TEXT"),
+    ("STABILITY", "REAL"),
+    ("DIFFICULTY", "REAL"),
+    ("ELAPSED_DAYS", "INTEGER"),
+    ("SCHEDULED_DAYS", "INTEGER"),
+    ("REPS", "INTEGER"),
+    ("LAPSES", "INTEGER"),
+    ("STATE", "INTEGER"),
+    ("LAST_REVIEW", "TEXT"),
+    ("PREVIOUS_STATE", "INTEGER"),
+    ("LOG_RATING", "INTEGER"),
+    ("LOG_ELAPSED_DAYS", "INTEGER"),
+    ("LOG_SCHEDULED_DAYS", "INTEGER"),
+    ("LOG_STATE", "INTEGER"),
+    ("LOG_REVIEWED_DATE", "TEXT"),
+    ("FIRST_STUDY", "TEXT"),
+    ("SUSPENDED", "INTEGER NOT NULL DEFAULT 0"),
+    ("BURIED_UNTIL", "TEXT"),
+    ("TAGS", "TEXT NOT NULL DEFAULT ''"),
+    ("NOTE", "INTEGER"),
+    ("TEMPLATE", "INTEGER NOT NULL DEFAULT 0"),
+    ("REVERSED", "INTEGER NOT NULL DEFAULT 0"),
+    ("GUID", "TEXT"),
+    ("ADDED", "TEXT")
+];
+
+/// Options and metadata for each deck, keyed by the deck's name. Decks without a row use the defaults.
+pub const DECKS: &str = "srs_decks";
+
+/// Every column of `DECKS` after the name, along with its type, so that `migrate` can add new ones.
+const DECK_COLUMNS: [(&str, &str); 10] = [
+    ("REVERSE", "INTEGER NOT NULL DEFAULT 0"),
+    ("DESCRIPTION", "TEXT NOT NULL DEFAULT ''"),
+    ("AUTHOR", "TEXT NOT NULL DEFAULT ''"),
+    ("SOURCE", "TEXT NOT NULL DEFAULT ''"),
+    ("LICENSE", "TEXT NOT NULL DEFAULT ''"),
+    ("LANGUAGE", "TEXT NOT NULL DEFAULT ''"),
+    ("VERSION", "TEXT NOT NULL DEFAULT ''"),
+    ("CREATED", "TEXT"),
+    ("MODIFIED", "TEXT"),
+    ("LEECH_THRESHOLD", "INTEGER")
+];
+
+/// The metadata of a deck that can be edited, which are the keys of `get_deck_metadata` in lowercase along with `created`
+/// and `modified`.
+pub const METADATA: [&str; 6] = ["DESCRIPTION", "AUTHOR", "SOURCE", "LICENSE", "LANGUAGE", "VERSION"];
+
+/// How many names `create_deck` tries before giving up on finding a free one.
+const MAX_NAME_ATTEMPTS: u64 = 1000;
+
+/// Tables in the collection that aren't decks.
+const INTERNAL_TABLES: [&str; 4] = [notes::NOTE_TYPES, notes::NOTES, DECKS, reviews::REVIEWS];
+
+/// How `import_deck` treats a file's cards.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    /// An existing deck to merge the file into, instead of creating a new deck.
+    pub deck: Option<String>,
+    /// Whether merging deletes the cards in the deck that aren't in the file.
+    pub remove: bool,
+    /// `skip`, `update` or `keep` cards whose obverse is already in the decks the `duplicate_scope` setting searches. With
+    /// the `deck` scope, an import into a new deck only finds the duplicates within the file, since the deck starts empty.
+    pub duplicates: String,
+    /// How the columns of a CSV or TSV file become cards.
+    pub columns: delimited::Options,
+    /// Whether the FSRS state of an Anki package's cards is rebuilt from their review history.
+    pub history: bool,
+    /// Whether to only work out what the import would do, without changing anything.
+    pub dry_run: bool
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions { ImportOptions { deck: None, remove: false, duplicates: "keep".to_string(), columns: delimited::Options::default(), history: true, dry_run: false } }
+}
+
+/// How many cards an import added, updated, skipped as duplicates and removed, or would have for a dry run.
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub removed: usize
+}
+
+/// How `export_deck` writes a deck.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ExportOptions {
+    /// `json`, `srs`, `srspkg`, `apkg`, `csv`, `tsv`, `md` or `txt`. Taken from the extension of the path if not set.
+    pub format: Option<String>,
+    /// Whether cards exported to Anki or as a package keep their progress, and whether CSV and TSV files have scheduling
+    /// columns.
+    pub scheduling: bool,
+    /// What a package says about the deck.
+    pub manifest: package::Manifest
+}
+
+/// A card in the review queue, as (deck, ID, obverse, reverse, FSRS state).
+pub type QueuedCard = (String, i64, String, String, Card);
+
+/// A card in a deck or search results, as (deck, ID, obverse, reverse, FSRS state, other properties).
+pub type ListedCard = (String, i64, String, String, serde_json::Value, serde_json::Value);
+
+/// A note changed by a find and replace, as (ID, fields before, fields after).
+pub type FieldChange = (i64, Vec<String>, Vec<String>);
+
+pub struct Cards { pub cards: Mutex<Vec<QueuedCard>> }
 
 impl Cards {
-    pub fn default() -> Cards { Cards { cards: Mutex::from(Self::get_cards()) } }
+    pub fn default() -> Cards {
+        Self::migrate();
+        Cards { cards: Mutex::from(Self::get_cards()) }
+    }
 
     //noinspection DuplicatedCode
-    fn get_path() -> String {
+    pub fn get_path() -> String {
         let db_path = dirs::data_dir().unwrap().to_str().unwrap().to_string() + "/srs/srs.sqlite";
         let db_file = Path::new(&db_path);
         if !db_file.exists() {
@@ -37,24 +157,86 @@ impl Cards {
         db_path
     }
 
-    fn get_cards() -> Vec<(String, i64, String, String, Card)> {
+    fn structure() -> String { format!("({})", COLUMNS.iter().map(|(name, kind)| format!("{} {}", name, kind)).collect::<Vec<String>>().join(", ")) }
+
+    /// Adds any columns missing from decks created by older versions, and makes a Basic note and a GUID for cards that predate
+    /// them.
+    pub fn migrate() {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        notes::migrate(&connection);
+        reviews::migrate(&connection);
+        connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (NAME TEXT PRIMARY KEY, REVERSE INTEGER NOT NULL DEFAULT 0)", DECKS)).unwrap();
+        let mut existing: Vec<String> = Vec::new();
+        let mut statement = connection.prepare(format!("PRAGMA table_info({})", DECKS)).unwrap();
+        while let Ok(DBState::Row) = statement.next() { existing.push(statement.read::<String, _>("name").unwrap()); }
+        for (name, kind) in DECK_COLUMNS.iter().filter(|(name, _)| !existing.contains(&name.to_string())) {
+            connection.execute(format!("ALTER TABLE {} ADD COLUMN {} {}", DECKS, name, kind)).unwrap();
+        }
+        connection.execute("BEGIN").unwrap();
+        for deck in Self::get_decks() {
+            let mut existing: Vec<String> = Vec::new();
+            let mut statement = connection.prepare(format!("PRAGMA table_info(\"{}\")", deck.replace(r#"""#, r#""""#))).unwrap();
+            while let Ok(DBState::Row) = statement.next() { existing.push(statement.read::<String, _>("name").unwrap()); }
+            for (name, kind) in COLUMNS.iter().filter(|(name, _)| !existing.contains(&name.to_string())) {
+                connection.execute(format!("ALTER TABLE \"{}\" ADD COLUMN {} {}", deck.replace(r#"""#, r#""""#), name, kind)).unwrap();
+            }
+
+            let mut cards: Vec<(i64, String, String)> = Vec::new();
+            let mut statement = connection.prepare(format!("SELECT ROWID, OBVERSE, REVERSE FROM \"{}\" WHERE NOTE IS NULL", deck.replace(r#"""#, r#""""#))).unwrap();
+            while let Ok(DBState::Row) = statement.next() {
+                cards.push((statement.read::<i64, _>("rowid").unwrap(), statement.read::<String, _>("OBVERSE").unwrap(), statement.read::<String, _>("REVERSE").unwrap()));
+            }
+            for (id, obverse, reverse) in cards {
+                let note = notes::insert_note(&connection, notes::BASIC, &[obverse, reverse]);
+                connection.execute(format!("UPDATE \"{}\" SET NOTE = {}, TEMPLATE = 0 WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), note, id)).unwrap();
+            }
+
+            let mut ids: Vec<i64> = Vec::new();
+            let mut statement = connection.prepare(format!("SELECT ROWID FROM \"{}\" WHERE GUID IS NULL", deck.replace(r#"""#, r#""""#))).unwrap();
+            while let Ok(DBState::Row) = statement.next() { ids.push(statement.read::<i64, _>("rowid").unwrap()); }
+            for id in ids { connection.execute(format!("UPDATE \"{}\" SET GUID = '{}' WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), Self::new_guid(), id)).unwrap(); }
+        }
+        connection.execute("COMMIT").unwrap();
+    }
+
+    /// The hour in local time at which a new day starts, so that studying past midnight still counts for the day before.
+    pub fn day_boundary() -> i64 { settings::Settings::get_from_file("day_boundary").as_i64().unwrap().clamp(0, 23) }
+
+    /// The day a time falls on, for days that start at `boundary` as returned by `day_boundary`.
+    pub fn day(time: DateTime<Utc>, boundary: i64) -> NaiveDate { (time.with_timezone(&Local) - Duration::hours(boundary)).date_naive() }
+
+    /// The start of the current day, which is when new card limits and burials reset.
+    pub fn day_start() -> DateTime<Utc> {
+        let boundary = Self::day_boundary();
+        let start = Self::day(Utc::now(), boundary).and_hms_opt(boundary as u32, 0, 0).unwrap();
+        // The boundary can fall in the hour skipped when clocks go forward.
+        Local.from_local_datetime(&start).earliest()
+            .or_else(|| Local.from_local_datetime(&(start + Duration::hours(1))).earliest())
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn get_cards() -> Vec<QueuedCard> {
         let connection = Connection::open(Self::get_path()).unwrap();
         let decks: Vec<String> = Self::get_decks();
-        let mut cards: Vec<(String, i64, String, String, Card)> = Vec::new();
+        let mut cards: Vec<QueuedCard> = Vec::new();
+        let day_start = Self::day_start();
 
         for deck in decks {
             let mut new_cards: i64 = 0;
             let mut statement = connection.prepare(format!("SELECT FIRST_STUDY FROM \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
             while let Ok(DBState::Row) = statement.next() {
-                if statement.read::<String, _>("FIRST_STUDY").is_ok() {
-                    if <DateTime<Utc> as PartialOrd<DateTime<Utc>>>::gt(
-                        &DateTime::from(DateTime::parse_from_rfc3339(&statement.read::<String, _>("FIRST_STUDY").unwrap()).unwrap()),
-                        &DateTime::from_naive_utc_and_offset(Utc::now().date_naive().and_time(chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()), Utc)
-                    ) { new_cards += 1; }
-                }
+                if statement.read::<String, _>("FIRST_STUDY").is_ok() && <DateTime<Utc> as PartialOrd<DateTime<Utc>>>::gt(
+                    &DateTime::from(DateTime::parse_from_rfc3339(&statement.read::<String, _>("FIRST_STUDY").unwrap()).unwrap()),
+                    &day_start
+                ) { new_cards += 1; }
             }
             let mut statement = connection.prepare(format!("SELECT ROWID, * FROM \"{}\" ORDER BY DUE", deck.replace(r#"""#, r#""""#))).unwrap();
             while let Ok(DBState::Row) = statement.next() {
+                if statement.read::<i64, _>("SUSPENDED").unwrap() != 0 { continue; }
+                if let Ok(buried_until) = statement.read::<String, _>("BURIED_UNTIL") {
+                    if DateTime::<Utc>::from(DateTime::parse_from_rfc3339(&buried_until).unwrap()) > Utc::now() { continue; }
+                }
                 if <DateTime<Utc> as PartialOrd<DateTime<Utc>>>::le(
                     &DateTime::from(DateTime::parse_from_rfc3339(&statement.read::<String, _>("DUE").unwrap()).unwrap()),
                     &Utc::now()
@@ -62,14 +244,19 @@ impl Cards {
                 if statement.read::<i64, _>("STATE").unwrap() == 1 {new_cards += 1}
             }
         }
+        // Cards are otherwise reviewed deck by deck in order of when they're due. Cards not in review have no retrievability.
+        if settings::Settings::get_from_file("review_order") == "retrievability" {
+            let now = Utc::now();
+            cards.sort_by(|a, b| Self::retrievability(&a.4, now).unwrap_or(f64::INFINITY).total_cmp(&Self::retrievability(&b.4, now).unwrap_or(f64::INFINITY)));
+        }
 
         cards
     }
 
     fn refresh(&self) { *(self.cards.lock().unwrap()) = Self::get_cards(); }
 
-    fn add_card_to_vec(cards: &mut Vec<(String, i64, String, String, Card)>, statement: &mut Statement, deck: &String) {
-        cards.push((deck.clone(), statement.read::<i64, _>("rowid").unwrap(), statement.read::<String, _>("OBVERSE").unwrap().as_str().to_string(), statement.read::<String, _>("REVERSE").unwrap().as_str().to_string(), Card {
+    fn add_card_to_vec(cards: &mut Vec<QueuedCard>, statement: &mut Statement, deck: &str) {
+        cards.push((deck.to_string(), statement.read::<i64, _>("rowid").unwrap(), statement.read::<String, _>("OBVERSE").unwrap().as_str().to_string(), statement.read::<String, _>("REVERSE").unwrap().as_str().to_string(), Card {
             due: DateTime::from(DateTime::parse_from_rfc3339(&statement.read::<String, _>("DUE").unwrap()).unwrap()),
             stability: statement.read::<f64, _>("STABILITY").unwrap() as f32,
             difficulty: statement.read::<f64, _>("DIFFICULTY").unwrap() as f32,
@@ -119,11 +306,39 @@ impl Cards {
 
     pub fn card_count(&self) -> i64 { self.cards.lock().unwrap().len() as i64 }
 
-    pub fn current_card(&self) -> Result<(String, i64, String, String, Card), ()> { Ok(self.cards.lock().unwrap().first().ok_or(())?.to_owned()) }
+    pub fn current_card(&self) -> Result<QueuedCard, ()> { Ok(self.cards.lock().unwrap().first().ok_or(())?.to_owned()) }
 
-    pub fn update_card(&self, deck: String, id: i64, card: &Card) {
+    /// Returns whether the card became a leech and the user should be notified of it. `duration` is how long the answer took
+    /// in milliseconds, if known, and is kept with the review.
+    pub fn update_card(&self, deck: String, id: i64, card: &Card, duration: Option<i64>) -> bool {
         let connection = Connection::open(Self::get_path()).unwrap();
-        connection.execute(format!("UPDATE \"{}\" SET DUE = '{}', STABILITY = {}, DIFFICULTY = {}, ELAPSED_DAYS = {}, SCHEDULED_DAYS = {}, REPS = {}, LAPSES = {}, STATE = {}, LAST_REVIEW = '{}', PREVIOUS_STATE = {}, LOG_RATING = {}, LOG_ELAPSED_DAYS = {}, LOG_SCHEDULED_DAYS = {}, LOG_STATE = {}, LOG_REVIEWED_DATE = '{}' WHERE ROWID = {}",
+        let mut statement = connection.prepare(format!("SELECT LAPSES FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        statement.next().unwrap();
+        let lapses = statement.read::<i64, _>("LAPSES").unwrap();
+        Self::write_card(&connection, &deck, id, card);
+        reviews::log(&connection, &Self::guid(&connection, &deck, id), card, duration);
+
+        if connection.prepare(format!("SELECT FIRST_STUDY FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap().read::<String, _>("FIRST_STUDY").is_err() {
+            connection.execute(format!("UPDATE \"{}\" SET FIRST_STUDY = '{}' WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), Utc::now().to_rfc3339(), id)).unwrap();
+        }
+
+        let leech = card.lapses as i64 > lapses && Self::is_leech(Self::leech_threshold(&connection, &deck), card.lapses as i64);
+        let actions = settings::Settings::get_from_file("leech_actions");
+        let actions: Vec<&str> = actions.as_array().map(|actions| actions.iter().filter_map(serde_json::Value::as_str).collect()).unwrap_or(vec!["tag"]);
+        if leech && actions.contains(&"tag") { Self::add_tag(&connection, &deck, id, "leech"); }
+        if leech && actions.contains(&"suspend") { connection.execute(format!("UPDATE \"{}\" SET SUSPENDED = 1 WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap(); }
+
+        self.refresh();
+        leech && actions.contains(&"notify")
+    }
+
+    /// Writes the FSRS state of a card. Cards without a log, like ones that were never reviewed, have their log cleared.
+    pub fn write_card(connection: &Connection, deck: &str, id: i64, card: &Card) {
+        let log: [String; 5] = match &card.log {
+            Some(log) => [Self::rating_number(log.rating).to_string(), log.elapsed_days.to_string(), log.scheduled_days.to_string(), Self::state_number(log.state).to_string(), format!("'{}'", log.reviewed_date.to_rfc3339())],
+            None => ["NULL", "NULL", "NULL", "NULL", "NULL"].map(str::to_string)
+        };
+        connection.execute(format!("UPDATE \"{}\" SET DUE = '{}', STABILITY = {}, DIFFICULTY = {}, ELAPSED_DAYS = {}, SCHEDULED_DAYS = {}, REPS = {}, LAPSES = {}, STATE = {}, LAST_REVIEW = '{}', PREVIOUS_STATE = {}, LOG_RATING = {}, LOG_ELAPSED_DAYS = {}, LOG_SCHEDULED_DAYS = {}, LOG_STATE = {}, LOG_REVIEWED_DATE = {} WHERE ROWID = {}",
                                    deck.replace(r#"""#, r#""""#),
                                    card.due.to_rfc3339(),
                                    card.stability,
@@ -132,99 +347,436 @@ impl Cards {
                                    card.scheduled_days,
                                    card.reps,
                                    card.lapses,
-                                   match card.state {
-                                       CardState::New => 1,
-                                       CardState::Learning => 2,
-                                       CardState::Review => 3,
-                                       CardState::Relearning => 4
-                                   },
+                                   Self::state_number(card.state),
                                    card.last_review.to_rfc3339(),
-                                   match card.previous_state {
-                                       CardState::New => 1,
-                                       CardState::Learning => 2,
-                                       CardState::Review => 3,
-                                       CardState::Relearning => 4
-                                   },
-                                   match card.log.clone().unwrap().rating {
-                                       Rating::Again => 1,
-                                       Rating::Hard => 2,
-                                       Rating::Good => 3,
-                                       Rating::Easy => 4
-                                   },
-                                   card.log.clone().unwrap().elapsed_days,
-                                   card.log.clone().unwrap().scheduled_days,
-                                   match card.log.clone().unwrap().state {
-                                       CardState::New => 1,
-                                       CardState::Learning => 2,
-                                       CardState::Review => 3,
-                                       CardState::Relearning => 4
-                                   },
-                                   card.log.clone().unwrap().reviewed_date.to_rfc3339(),
+                                   Self::state_number(card.previous_state),
+                                   log[0],
+                                   log[1],
+                                   log[2],
+                                   log[3],
+                                   log[4],
                                    id
         )).unwrap();
+    }
 
-        if connection.prepare(format!("SELECT FIRST_STUDY FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap().read::<String, _>("FIRST_STUDY").is_err() {
-            connection.execute(format!("UPDATE \"{}\" SET FIRST_STUDY = '{}' WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), Utc::now().to_rfc3339(), id)).unwrap();
+    /// A random id for a card, which stays the same when it's moved to another deck or exported.
+    pub fn new_guid() -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        format!("{:x}", Sha1::digest(format!("{:?} {} {}", SystemTime::now(), std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed))))[..16].to_string()
+    }
+
+    pub fn guid(connection: &Connection, deck: &str, id: i64) -> String {
+        let mut statement = connection.prepare(format!("SELECT GUID FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        statement.next().unwrap();
+        statement.read::<String, _>("GUID").unwrap()
+    }
+
+    /// Finds the card with a GUID as (deck, id).
+    pub fn find_guid(connection: &Connection, guid: &str) -> Option<(String, i64)> { Self::list_decks(connection).into_iter().find_map(|deck| Self::deck_guid(connection, &deck, guid).map(|id| (deck, id))) }
+
+    /// Finds the card with a GUID in a deck.
+    pub fn deck_guid(connection: &Connection, deck: &str, guid: &str) -> Option<i64> {
+        let mut statement = connection.prepare(format!("SELECT ROWID FROM \"{}\" WHERE GUID = '{}'", deck.replace(r#"""#, r#""""#), guid.replace('\'', "''"))).unwrap();
+        match statement.next() {
+            Ok(DBState::Row) => Some(statement.read::<i64, _>("rowid").unwrap()),
+            _ => None
         }
+    }
 
-        self.refresh();
+    pub fn state_number(state: CardState) -> i64 {
+        match state {
+            CardState::New => 1,
+            CardState::Learning => 2,
+            CardState::Review => 3,
+            CardState::Relearning => 4
+        }
     }
 
-    pub fn get_decks() -> Vec<String> {
-        let connection = Connection::open(Self::get_path()).unwrap();
+    pub fn rating_number(rating: Rating) -> i64 {
+        match rating {
+            Rating::Again => 1,
+            Rating::Hard => 2,
+            Rating::Good => 3,
+            Rating::Easy => 4
+        }
+    }
+
+    /// A card becomes a leech when its lapses reach the threshold, and again every half threshold after that.
+    pub fn is_leech(threshold: i64, lapses: i64) -> bool {
+        threshold > 0 && lapses >= threshold && (lapses - threshold) % (threshold / 2).max(1) == 0
+    }
+
+    fn add_tag(connection: &Connection, deck: &str, id: i64, tag: &str) {
+        let mut statement = connection.prepare(format!("SELECT TAGS FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        statement.next().unwrap();
+        let mut tags: Vec<String> = statement.read::<String, _>("TAGS").unwrap().split_whitespace().map(str::to_string).collect();
+        if !tags.contains(&tag.to_string()) { tags.push(tag.to_string()); }
+        connection.execute(format!("UPDATE \"{}\" SET TAGS = '{}' WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), tags.join(" ").replace('\'', "''"), id)).unwrap();
+    }
+
+    pub fn get_decks() -> Vec<String> { Self::list_decks(&Connection::open(Self::get_path()).unwrap()) }
+
+    /// Like `get_decks`, but sees decks created by an uncommitted transaction on `connection`.
+    pub fn list_decks(connection: &Connection) -> Vec<String> {
         let mut statement = connection.prepare("SELECT * FROM sqlite_master WHERE TYPE='table'").unwrap();
         let mut decks: Vec<String> = Vec::new();
         while let Ok(DBState::Row) = statement.next() { decks.push(statement.read::<String, _>("tbl_name").unwrap()); }
+        decks.retain(|deck| !INTERNAL_TABLES.contains(&deck.as_str()));
 
         decks
     }
 
-    pub fn new_deck(deck: String) { Connection::open(Self::get_path()).unwrap().execute(format!("CREATE TABLE \"{}\" (OBVERSE TEXT, REVERSE TEXT, DUE TEXT, STABILITY REAL, DIFFICULTY REAL, ELAPSED_DAYS INTEGER, SCHEDULED_DAYS INTEGER, REPS INTEGER, LAPSES INTEGER, STATE INTEGER, LAST_REVIEW TEXT, PREVIOUS_STATE INTEGER, LOG_RATING INTEGER, LOG_ELAPSED_DAYS INTEGER, LOG_SCHEDULED_DAYS INTEGER, LOG_STATE INTEGER, LOG_REVIEWED_DATE TEXT, FIRST_STUDY TEXT)", deck.replace(r#"""#, r#""""#))).unwrap(); }
+    pub fn new_deck(deck: String) {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute(format!("CREATE TABLE \"{}\" {}", deck.replace(r#"""#, r#""""#), Self::structure())).unwrap();
+        Self::register_deck(&connection, &deck);
+    }
+
+    /// Creates a deck named `name`, or `name(1)`, `name(2)`... if that name is taken, returning the name it was given. Names
+    /// SQLite refuses for other reasons, like ones starting with `sqlite_`, are an error.
+    pub fn create_deck(connection: &Connection, name: &str) -> Result<String, String> {
+        let mut deck_name = name.to_string();
+        for attempt in 1..=MAX_NAME_ATTEMPTS {
+            match connection.execute(format!("CREATE TABLE \"{}\" {}", deck_name.replace(r#"""#, r#""""#), Self::structure())) {
+                Ok(_) => {
+                    Self::register_deck(connection, &deck_name);
+                    return Ok(deck_name);
+                }
+                Err(error) if error.message.as_deref().is_some_and(|message| message.contains("already exists")) => deck_name = format!("{}({})", name, attempt),
+                Err(error) => return Err(format!("A deck can't be named {}: {}", name, error))
+            }
+        }
+
+        Err(format!("There are too many decks named {}", name))
+    }
 
-    pub fn import_deck(&self, path: String) -> Result<(), ()> {
-        let mut content = String::default();
-        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
-        let card_vec: Vec<serde_json::Value> = serde_json::from_str(content.as_str()).unwrap();
+    /// Gives a new deck default options and metadata, created now.
+    fn register_deck(connection: &Connection, deck: &str) {
+        let now = Utc::now().to_rfc3339();
+        connection.execute(format!("INSERT OR REPLACE INTO {} (NAME, CREATED, MODIFIED) VALUES ('{}', '{}', '{}')", DECKS, deck.replace('\'', "''"), now, now)).unwrap();
+    }
+
+    /// Records that a deck's cards changed now.
+    pub fn touch(connection: &Connection, deck: &str) {
+        connection.execute(format!("INSERT OR IGNORE INTO {} (NAME) VALUES ('{}')", DECKS, deck.replace('\'', "''"))).unwrap();
+        connection.execute(format!("UPDATE {} SET MODIFIED = '{}' WHERE NAME = '{}'", DECKS, Utc::now().to_rfc3339(), deck.replace('\'', "''"))).unwrap();
+    }
+
+    /// Every deck along with its metadata.
+    pub fn get_decks_with_metadata() -> Vec<(String, serde_json::Value)> {
         let connection = Connection::open(Self::get_path()).unwrap();
-        let structure = "\n(
-    OBVERSE            TEXT,
-    REVERSE            TEXT,
-    DUE                TEXT,
-    STABILITY          REAL,
-    DIFFICULTY         REAL,
-    ELAPSED_DAYS       INTEGER,
-    SCHEDULED_DAYS     INTEGER,
-    REPS               INTEGER,
-    LAPSES             INTEGER,
-    STATE              INTEGER,
-    LAST_REVIEW        TEXT,
-    PREVIOUS_STATE     INTEGER,
-    LOG_RATING         INTEGER,
-    LOG_ELAPSED_DAYS   INTEGER,
-    LOG_SCHEDULED_DAYS INTEGER,
-    LOG_STATE          INTEGER,
-    LOG_REVIEWED_DATE  TEXT,
-    FIRST_STUDY        TEXT
-)";
-        let file_name = Path::new(path.as_str()).file_name().ok_or(())?.to_str().unwrap();
-        let mut deck_name = String::default();
-        if connection.execute(format!("CREATE TABLE \"{}\"", file_name.replace(r#"""#, r#""""#)) + structure).is_err() {
-            loop {
-                let mut att: u64 = 1;
-                if connection.execute(format!("CREATE TABLE \"{}({})\"", file_name.replace(r#"""#, r#""""#), att) + structure).is_ok() {
-                    deck_name = format!("{}({})", file_name, att);
-                    break;
-                } else { att += 1; }
+        Self::list_decks(&connection).into_iter().map(|deck| {
+            let metadata = Self::deck_metadata(&connection, &deck);
+            (deck, metadata)
+        }).collect()
+    }
+
+    pub fn get_deck_metadata(deck: String) -> serde_json::Value { Self::deck_metadata(&Connection::open(Self::get_path()).unwrap(), &deck) }
+
+    /// A deck's description, author, source URL, license, language, version and when it was created and last changed. The
+    /// times are `null` if they aren't known.
+    pub fn deck_metadata(connection: &Connection, deck: &str) -> serde_json::Value {
+        let mut statement = connection.prepare(format!("SELECT * FROM {} WHERE NAME = '{}'", DECKS, deck.replace('\'', "''"))).unwrap();
+        let found = matches!(statement.next(), Ok(DBState::Row));
+        let mut metadata = serde_json::Map::new();
+        for name in METADATA {
+            metadata.insert(name.to_lowercase(), serde_json::json!(if found { statement.read::<String, _>(name).unwrap() } else { String::default() }));
+        }
+        for name in ["CREATED", "MODIFIED"] {
+            metadata.insert(name.to_lowercase(), serde_json::json!(if found { statement.read::<Option<String>, _>(name).unwrap() } else { None }));
+        }
+
+        serde_json::Value::Object(metadata)
+    }
+
+    pub fn set_deck_metadata(deck: String, metadata: serde_json::Value) { Self::write_deck_metadata(&Connection::open(Self::get_path()).unwrap(), &deck, &metadata); }
+
+    /// Sets the keys of `metadata` that are in `METADATA` in lowercase, leaving the others as they are.
+    pub fn write_deck_metadata(connection: &Connection, deck: &str, metadata: &serde_json::Value) {
+        Self::touch(connection, deck);
+        for name in METADATA {
+            let Some(value) = metadata[name.to_lowercase()].as_str() else { continue; };
+            connection.execute(format!("UPDATE {} SET {} = '{}' WHERE NAME = '{}'", DECKS, name, value.trim().replace('\'', "''"), deck.replace('\'', "''"))).unwrap();
+        }
+    }
+
+    /// Normalizes an obverse for duplicate detection, ignoring Markdown emphasis, ruby readings, case and whitespace.
+    pub fn normalize(text: &str) -> String {
+        let mut normalized = String::default();
+        let (mut ruby, mut reading) = (false, false);
+        for char in text.chars() {
+            match char {
+                '{' => ruby = true,
+                '|' if ruby => reading = true,
+                '}' => (ruby, reading) = (false, false),
+                '*' | '_' | '~' | '`' => (),
+                _ if !reading => normalized.push(char),
+                _ => ()
             }
-        } else { deck_name = file_name.to_string(); }
+        }
 
-        for card in card_vec { self.new_card(deck_name.to_string(), str::replace(card[0].as_str().unwrap(), "\\n", "\n").to_string(), str::replace(card[1].as_str().unwrap(), "\\n", "\n").to_string())?; }
-        self.refresh();
-        Ok(())
+        normalized.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+    }
+
+    /// The decks searched for duplicates of a card in `deck`, depending on the `duplicate_scope` setting.
+    fn duplicate_scope(connection: &Connection, deck: &str) -> Vec<String> {
+        match settings::Settings::get_from_file("duplicate_scope").as_str().unwrap() {
+            "collection" => Self::list_decks(connection),
+            _ => vec![deck.to_string()]
+        }
+    }
+
+    /// Maps the normalized obverse of the first card of every note in `decks` to those cards as (deck, id, note). Reversed
+    /// cards and the other cards of a note aren't included, and neither are empty obverses.
+    fn obverse_index(connection: &Connection, decks: &[String]) -> HashMap<String, Vec<(String, i64, i64)>> {
+        let mut index: HashMap<String, Vec<(String, i64, i64)>> = HashMap::new();
+        for deck in decks {
+            let mut statement = connection.prepare(format!("SELECT ROWID, OBVERSE, NOTE FROM \"{}\" WHERE TEMPLATE = 0 AND REVERSED = 0", deck.replace(r#"""#, r#""""#))).unwrap();
+            while let Ok(DBState::Row) = statement.next() {
+                let obverse = Self::normalize(&statement.read::<String, _>("OBVERSE").unwrap());
+                if obverse.is_empty() { continue; }
+                index.entry(obverse).or_default().push((deck.clone(), statement.read::<i64, _>("rowid").unwrap(), statement.read::<i64, _>("NOTE").unwrap()));
+            }
+        }
+
+        index
+    }
+
+    /// Returns the cards of other notes with the same normalized obverse as the first card of a note.
+    fn note_duplicates(connection: &Connection, note: i64) -> Vec<(String, i64)> {
+        let (deck, id, _, _) = match notes::note_cards(connection, note).into_iter().find(|card| card.2 == 0 && !card.3) {
+            Some(card) => card,
+            None => return Vec::new()
+        };
+        let mut statement = connection.prepare(format!("SELECT OBVERSE FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        statement.next().unwrap();
+        let obverse = Self::normalize(&statement.read::<String, _>("OBVERSE").unwrap());
+
+        Self::obverse_index(connection, &Self::duplicate_scope(connection, &deck))
+            .remove(&obverse)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|card| card.2 != note)
+            .map(|card| (card.0, card.1))
+            .collect()
+    }
+
+    /// Groups cards with the same normalized obverse, either within `deck` or across the whole collection if it is `None`.
+    pub fn find_duplicates(deck: Option<String>) -> Vec<Vec<(String, i64, String)>> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        let decks = match deck {
+            Some(deck) => vec![deck],
+            None => Self::list_decks(&connection)
+        };
+        let mut duplicates: Vec<Vec<(String, i64, String)>> = Vec::new();
+        for (_, cards) in Self::obverse_index(&connection, &decks).into_iter().filter(|(_, cards)| cards.len() > 1) {
+            duplicates.push(cards.into_iter().map(|(deck, id, _)| {
+                let mut statement = connection.prepare(format!("SELECT OBVERSE FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+                statement.next().unwrap();
+                let obverse = statement.read::<String, _>("OBVERSE").unwrap();
+                (deck, id, obverse)
+            }).collect());
+        }
+
+        duplicates
     }
 
+    /// Imports a JSON array of [obverse, reverse] pairs, a CSV or TSV file whose columns are mapped to cards by `options`, a
+    /// deck in the srs deck format, a package or an Anki package.
+    ///
+    /// Files are imported into a new deck named after the file, unless `options.deck` names an existing deck to merge them
+    /// into, which Anki packages can't be. When merging, cards are matched to the deck's cards by GUID, or otherwise by their
+    /// normalized obverse, and matched cards have their content updated while keeping their progress. With `options.remove`,
+    /// cards in the deck that no file card matched are deleted.
+    ///
+    /// `duplicates` decides what happens to cards whose obverse is already in the collection but weren't matched: `skip`
+    /// ignores them, `update` replaces the reverse of the existing card and `keep` adds them anyway. Anki packages keep all of
+    /// their notes, since they bring their own note types.
+    ///
+    /// The import runs in a single transaction, so if anything is wrong with the file, every problem found is returned and
+    /// the collection is left as it was. With `options.dry_run`, the transaction is always rolled back, so the report
+    /// previews the import.
+    pub fn import_deck(&self, path: String, options: ImportOptions) -> Result<ImportReport, Vec<String>> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        if let Some(deck) = &options.deck {
+            if !Self::list_decks(&connection).contains(deck) { return Err(vec![format!("There is no deck named {}", deck)]); }
+        }
+
+        connection.execute("BEGIN").unwrap();
+        let result = match Path::new(&path).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase).as_deref() {
+            Some("csv") | Some("tsv") | Some("txt") => delimited::read(&path, &options.columns)
+                .map_err(|error| vec![error])
+                .and_then(|card_vec| Self::import_cards(&connection, &path, card_vec, &options)),
+            Some("srs") => deck_file::import(&connection, &path, options.deck.as_deref(), options.remove).map_err(|error| vec![error]),
+            Some("srspkg") => package::import(&connection, &path, options.deck.as_deref(), options.remove, !options.dry_run),
+            Some("apkg") | Some("colpkg") if options.deck.is_none() => anki::import(&connection, &path, options.history, !options.dry_run).map_err(|error| vec![error]),
+            Some("apkg") | Some("colpkg") => Err(vec!["Anki packages can only be imported as new decks".to_string()]),
+            _ => Self::read_pairs(&path).and_then(|card_vec| Self::import_cards(&connection, &path, card_vec, &options))
+        };
+        if result.is_ok() && !options.dry_run {
+            connection.execute("COMMIT").unwrap();
+            self.refresh();
+        } else { connection.execute("ROLLBACK").unwrap(); }
+
+        result
+    }
+
+    /// Updates a deck to a new version of it in the srs deck format or a package, keeping the progress of its cards as
+    /// `update::update` describes, and returns what changed. With `options.dry_run`, nothing is changed, so the result
+    /// previews the update.
+    pub fn update_deck(&self, deck: String, path: String, options: update::UpdateOptions) -> Result<serde_json::Value, Vec<String>> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        if !Self::list_decks(&connection).contains(&deck) { return Err(vec![format!("There is no deck named {}", deck)]); }
+        let file = match Path::new(&path).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase).as_deref() {
+            Some("srspkg") => package::read(&path, !options.dry_run)?,
+            _ => deck_file::read(&path).map_err(|error| vec![error])?
+        };
+
+        connection.execute("BEGIN").unwrap();
+        let result = update::update(&connection, &deck, &file, &options);
+        if result.is_ok() && !options.dry_run {
+            connection.execute("COMMIT").unwrap();
+            self.refresh();
+        } else { connection.execute("ROLLBACK").unwrap(); }
+
+        result
+    }
+
+    /// Reads a JSON array of [obverse, reverse] pairs, returning every malformed entry rather than stopping at the first.
+    fn read_pairs(path: &str) -> Result<Vec<delimited::Row>, Vec<String>> {
+        let content = fs::read_to_string(path).map_err(|error| vec![error.to_string()])?;
+        let file: serde_json::Value = serde_json::from_str(&content).map_err(|error| vec![format!("The file isn't valid JSON: {}", error)])?;
+        let entries = file.as_array().ok_or(vec!["The file isn't an array of [obverse, reverse] pairs".to_string()])?;
+
+        let mut card_vec = Vec::new();
+        let mut errors = Vec::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let Some(pair) = entry.as_array() else {
+                errors.push(format!("Card {}: isn't an [obverse, reverse] pair", index + 1));
+                continue;
+            };
+            let side = |position: usize, name: &str| match pair.get(position) {
+                Some(serde_json::Value::String(text)) => Ok(text.replace("\\n", "\n")),
+                Some(_) => Err(format!("Card {}: the {} isn't a string", index + 1, name)),
+                None => Err(format!("Card {}: the {} is missing", index + 1, name))
+            };
+            match (side(0, "obverse"), side(1, "reverse")) {
+                (Ok(obverse), Ok(reverse)) => card_vec.push((obverse, reverse, Vec::new(), None)),
+                (obverse, reverse) => errors.extend([obverse.err(), reverse.err()].into_iter().flatten())
+            }
+        }
+
+        if errors.is_empty() { Ok(card_vec) } else { Err(errors) }
+    }
+
+    /// Imports (obverse, reverse, tags, GUID) cards read from a file, as `import_deck` describes.
+    fn import_cards(connection: &Connection, path: &str, card_vec: Vec<delimited::Row>, options: &ImportOptions) -> Result<ImportReport, Vec<String>> {
+        let errors: Vec<String> = card_vec.iter().enumerate()
+            .filter(|(_, card)| card.0.trim().is_empty())
+            .map(|(index, _)| format!("Card {}: the obverse is empty", index + 1))
+            .collect();
+        if !errors.is_empty() { return Err(errors); }
+
+        let deck_name = match &options.deck {
+            Some(deck) => deck.clone(),
+            None => Self::create_deck(connection, Path::new(path).file_name().and_then(|name| name.to_str()).ok_or(vec!["The path has no file name".to_string()])?).map_err(|error| vec![error])?
+        };
+
+        // Only cards with Basic notes can be matched, since only their content maps onto an obverse and a reverse.
+        let mut by_guid: HashMap<String, i64> = HashMap::new();
+        let mut by_obverse: HashMap<String, i64> = HashMap::new();
+        if options.deck.is_some() {
+            let mut statement = connection.prepare(format!("SELECT CARD.ROWID, CARD.OBVERSE, CARD.GUID FROM \"{}\" AS CARD JOIN {} AS NOTE ON NOTE.ROWID = CARD.NOTE WHERE CARD.REVERSED = 0 AND NOTE.NOTE_TYPE = {}",
+                deck_name.replace(r#"""#, r#""""#),
+                notes::NOTES,
+                notes::BASIC
+            )).unwrap();
+            while let Ok(DBState::Row) = statement.next() {
+                let id = statement.read::<i64, _>("rowid").unwrap();
+                by_guid.insert(statement.read::<String, _>("GUID").unwrap(), id);
+                by_obverse.entry(Self::normalize(&statement.read::<String, _>("OBVERSE").unwrap())).or_insert(id);
+            }
+        }
+
+        let mut kept: Vec<i64> = Vec::new();
+        let mut report = ImportReport::default();
+        let mut errors: Vec<String> = Vec::new();
+        let mut index = Self::obverse_index(connection, &Self::duplicate_scope(connection, &deck_name));
+        for (position, (obverse, reverse, tags, guid)) in card_vec.into_iter().enumerate() {
+            let matched = guid.as_ref().and_then(|guid| by_guid.get(guid)).or_else(|| by_obverse.get(&Self::normalize(&obverse))).copied();
+            match (matched, options.duplicates.as_str(), index.get(&Self::normalize(&obverse)).and_then(|cards| cards.first())) {
+                (Some(id), _, _) => {
+                    kept.push(id);
+                    if Self::set_content(connection, &deck_name, id, obverse, reverse).is_err() {
+                        errors.push(format!("Card {}: only cards with Basic notes can be updated", position + 1));
+                        continue;
+                    }
+                    for tag in tags { Self::add_tag(connection, &deck_name, id, &tag); }
+                    report.updated += 1;
+                }
+                (None, "skip", Some(_)) => report.skipped += 1,
+                (None, "update", Some((deck, id, _))) => {
+                    let (deck, id) = (deck.clone(), *id);
+                    if deck == deck_name { kept.push(id); }
+                    if Self::set_content(connection, &deck, id, obverse, reverse).is_err() {
+                        errors.push(format!("Card {}: only cards with Basic notes can be updated", position + 1));
+                        continue;
+                    }
+                    for tag in tags { Self::add_tag(connection, &deck, id, &tag); }
+                    report.updated += 1;
+                }
+                _ => {
+                    let Some((_, id)) = Self::create_card(connection, &deck_name, obverse.clone(), reverse) else {
+                        errors.push(format!("Card {}: couldn't be created", position + 1));
+                        continue;
+                    };
+                    kept.push(id);
+                    for tag in tags { Self::add_tag(connection, &deck_name, id, &tag); }
+                    if let Some(guid) = guid.filter(|guid| Self::find_guid(connection, guid).is_none()) {
+                        connection.execute(format!("UPDATE \"{}\" SET GUID = '{}' WHERE ROWID = {}", deck_name.replace(r#"""#, r#""""#), guid.replace('\'', "''"), id)).unwrap();
+                    }
+                    if !Self::normalize(&obverse).is_empty() { index.entry(Self::normalize(&obverse)).or_default().push((deck_name.clone(), id, 0)); }
+                    report.added += 1;
+                }
+            }
+        }
+        if !errors.is_empty() { return Err(errors); }
+        if options.deck.is_some() && options.remove { report.removed = Self::remove_absent(connection, &deck_name, &kept); }
+
+        Ok(report)
+    }
+
+    /// Deletes the cards in a deck that aren't in `kept` or siblings of a card in it, for imports that replace a deck's
+    /// contents, returning how many were deleted.
+    pub fn remove_absent(connection: &Connection, deck: &str, kept: &[i64]) -> usize {
+        connection.execute(format!("DELETE FROM \"{0}\" WHERE (NOTE, TEMPLATE) NOT IN (SELECT NOTE, TEMPLATE FROM \"{0}\" WHERE ROWID IN ({1}))",
+            deck.replace(r#"""#, r#""""#),
+            kept.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+        )).unwrap();
+        let removed = connection.change_count();
+        Self::touch(connection, deck);
+        notes::clean(connection);
+        reviews::clean(connection);
+
+        removed
+    }
+
+    /// Exports a deck in the format named by `options.format` or the extension of `path`: the srs deck format, a package
+    /// for sharing, an Anki package, CSV or TSV, Markdown or a plain text list. Anything else is exported as a JSON array of
+    /// [obverse, reverse] pairs.
     //noinspection DuplicatedCode
-    pub fn export_deck(deck: String, path: String) {
+    pub fn export_deck(deck: String, path: String, options: ExportOptions) -> Result<(), String> {
+        let format = options.format.or_else(|| Path::new(&path).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase));
+        match format.as_deref() {
+            Some("apkg") => return anki::export(&deck, &path, options.scheduling),
+            Some("srs") => return deck_file::export(&deck, &path),
+            Some("srspkg") => return package::export(&deck, &path, options.manifest, options.scheduling),
+            Some("csv") => return delimited::write(&deck, &path, b',', options.scheduling),
+            Some("tsv") => return delimited::write(&deck, &path, b'\t', options.scheduling),
+            Some("md") | Some("markdown") => return text::markdown(&deck, &path),
+            Some("txt") => return text::plain(&deck, &path),
+            _ => ()
+        }
         let deck_full = Self::get_deck(deck);
         let mut deck: Vec<serde_json::Value> = Vec::new();
         for card in deck_full { deck.push(serde_json::json!([card.clone().2, card.clone().3])) }
@@ -241,38 +793,170 @@ impl Cards {
             .open(path).unwrap()
             .write_all(serde_json::Value::Array(deck).to_string().as_bytes())
             .unwrap();
+        Ok(())
     }
 
     pub fn rename_deck(&self, deck: String, name: String) {
-        Connection::open(Self::get_path()).unwrap().execute(format!("ALTER TABLE \"{}\" RENAME TO \"{}\"", deck.replace(r#"""#, r#""""#), name.replace(r#"""#, r#""""#))).unwrap();
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute(format!("ALTER TABLE \"{}\" RENAME TO \"{}\"", deck.replace(r#"""#, r#""""#), name.replace(r#"""#, r#""""#))).unwrap();
+        connection.execute(format!("UPDATE {} SET NAME = '{}' WHERE NAME = '{}'", DECKS, name.replace('\'', "''"), deck.replace('\'', "''"))).unwrap();
         self.refresh();
     }
 
     pub fn delete_deck(&self, deck: String) {
-        Connection::open(Self::get_path()).unwrap().execute(format!("DROP TABLE \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute(format!("DROP TABLE \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
+        connection.execute(format!("DELETE FROM {} WHERE NAME = '{}'", DECKS, deck.replace('\'', "''"))).unwrap();
+        notes::clean(&connection);
+        reviews::clean(&connection);
+        self.refresh();
+    }
+
+    pub fn get_deck_options(deck: String) -> serde_json::Value { Self::get_deck_options_with(&Connection::open(Self::get_path()).unwrap(), &deck) }
+
+    pub fn get_deck_options_with(connection: &Connection, deck: &str) -> serde_json::Value {
+        let mut statement = connection.prepare(format!("SELECT * FROM {} WHERE NAME = '{}'", DECKS, deck.replace('\'', "''"))).unwrap();
+        let (reverse, leech_threshold) = match statement.next() {
+            Ok(DBState::Row) => (statement.read::<i64, _>("REVERSE").unwrap() != 0, statement.read::<Option<i64>, _>("LEECH_THRESHOLD").unwrap()),
+            _ => (false, None)
+        };
+
+        serde_json::json!({ "reverse": reverse, "leech_threshold": leech_threshold })
+    }
+
+    /// The number of lapses at which a deck's cards become leeches: the deck's own `leech_threshold` option, or the
+    /// `leech_threshold` setting if it has none. Leeches are disabled if it's 0.
+    pub fn leech_threshold(connection: &Connection, deck: &str) -> i64 {
+        Self::get_deck_options_with(connection, deck)["leech_threshold"].as_i64()
+            .unwrap_or_else(|| settings::Settings::get_from_file("leech_threshold").as_i64().unwrap())
+    }
+
+    /// Sets the options present in `options`, leaving the others as they are. Enabling reverse cards creates a reversed
+    /// sibling for every card in the deck, and disabling it deletes them along with their reviews. A null
+    /// `leech_threshold` uses the `leech_threshold` setting.
+    pub fn set_deck_options(&self, deck: String, options: serde_json::Value) {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        let reversed = Self::get_deck_options_with(&connection, &deck)["reverse"] == true;
+        connection.execute("BEGIN").unwrap();
+        connection.execute(format!("INSERT OR IGNORE INTO {} (NAME) VALUES ('{}')", DECKS, deck.replace('\'', "''"))).unwrap();
+        if let Some(reverse) = options.get("reverse").and_then(serde_json::Value::as_bool) {
+            connection.execute(format!("UPDATE {} SET REVERSE = {} WHERE NAME = '{}'", DECKS, reverse as i64, deck.replace('\'', "''"))).unwrap();
+            if reverse {
+                let mut statement = connection.prepare(format!("SELECT DISTINCT NOTE FROM \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
+                let mut notes: Vec<i64> = Vec::new();
+                while let Ok(DBState::Row) = statement.next() { notes.push(statement.read::<i64, _>("NOTE").unwrap()); }
+                for note in notes { notes::sync_note(&connection, note, None); }
+            } else if reversed {
+                connection.execute(format!("DELETE FROM \"{}\" WHERE REVERSED = 1", deck.replace(r#"""#, r#""""#))).unwrap();
+                reviews::clean(&connection);
+            }
+        }
+        if let Some(leech_threshold) = options.get("leech_threshold") {
+            let leech_threshold = leech_threshold.as_i64().map(|threshold| threshold.max(0).to_string()).unwrap_or("NULL".to_string());
+            connection.execute(format!("UPDATE {} SET LEECH_THRESHOLD = {} WHERE NAME = '{}'", DECKS, leech_threshold, deck.replace('\'', "''"))).unwrap();
+        }
+        connection.execute("COMMIT").unwrap();
         self.refresh();
     }
 
-    pub fn get_deck(deck: String) -> Vec<(String, i64, String, String, serde_json::Value)>{
+    /// Returns every card in a deck, along with its FSRS state and properties that are not part of the scheduler, including
+    /// its current `retrievability`.
+    pub fn get_deck(deck: String) -> Vec<ListedCard>{
         let connection = Connection::open(Self::get_path()).unwrap();
         let mut statement = connection.prepare(format!("SELECT ROWID, * FROM \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
-        let mut cards: Vec<(String, i64, String, String, Card)> = Vec::new();
-        while let Ok(DBState::Row) = statement.next() { Self::add_card_to_vec(&mut cards, &mut statement, &deck); }
-        let mut deck: Vec<(String, i64, String, String, serde_json::Value)> = Vec::new();
-        for card in cards { deck.push((card.clone().0, card.clone().1, card.clone().2, card.clone().3, to_json(card.clone().4))); }
+        let mut cards: Vec<QueuedCard> = Vec::new();
+        let mut properties: Vec<serde_json::Value> = Vec::new();
+        while let Ok(DBState::Row) = statement.next() {
+            Self::add_card_to_vec(&mut cards, &mut statement, &deck);
+            properties.push(serde_json::json!({
+                "suspended": statement.read::<i64, _>("SUSPENDED").unwrap() != 0,
+                "buried_until": statement.read::<String, _>("BURIED_UNTIL").ok(),
+                "tags": statement.read::<String, _>("TAGS").unwrap().split_whitespace().collect::<Vec<&str>>(),
+                "note": statement.read::<i64, _>("NOTE").unwrap(),
+                "template": statement.read::<i64, _>("TEMPLATE").unwrap(),
+                "reversed": statement.read::<i64, _>("REVERSED").unwrap() != 0,
+                "guid": statement.read::<String, _>("GUID").unwrap()
+            }));
+        }
+        let now = Utc::now();
+        let mut deck: Vec<ListedCard> = Vec::new();
+        for (card, mut properties) in cards.into_iter().zip(properties) {
+            properties["retrievability"] = serde_json::json!(Self::retrievability(&card.4, now));
+            deck.push((card.clone().0, card.clone().1, card.clone().2, card.clone().3, to_json(card.clone().4), properties));
+        }
 
         deck
     }
 
-    pub fn new_card(&self, deck: String, obverse: String, reverse: String) -> Result<i64, ()> {
-        let card_json = to_json(Card::new());
+    /// The probability of recalling a card at `now`, as worked out by rs-fsrs with the model it schedules cards with. Only
+    /// cards in review have one.
+    pub fn retrievability(card: &Card, now: DateTime<Utc>) -> Option<f64> { card.get_retrievability(now).map(f64::from) }
+
+    /// Everything known about a card, for inspecting it: its content, deck, tags and other properties as in `get_deck`, when
+    /// it was `added` and first studied, its FSRS state, its current `retrievability`, every review in order with the
+    /// `interval` it set and the stability and difficulty after it, and the `average_time` of its answers in milliseconds.
+    /// Cards added before dates were kept have no `added` date.
+    pub fn card_info(deck: String, id: i64) -> Result<serde_json::Value, String> {
         let connection = Connection::open(Self::get_path()).unwrap();
+        let mut statement = connection.prepare(format!("SELECT ROWID, * FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).map_err(|error| error.to_string())?;
+        if !matches!(statement.next(), Ok(DBState::Row)) { return Err(format!("There is no card {} in {}", id, deck)); }
+        let mut cards: Vec<QueuedCard> = Vec::new();
+        Self::add_card_to_vec(&mut cards, &mut statement, &deck);
+        let (_, _, obverse, reverse, card) = cards.remove(0);
+        let guid = statement.read::<String, _>("GUID").unwrap();
+
+        let mut reviews = reviews::card_reviews(&connection, &guid);
+        for review in reviews.iter_mut() { review["interval"] = review["scheduled_days"].clone(); }
+        let durations: Vec<i64> = reviews.iter().filter_map(|review| review["duration"].as_i64()).collect();
+
+        Ok(serde_json::json!({
+            "deck": deck,
+            "id": id,
+            "guid": guid,
+            "obverse": obverse,
+            "reverse": reverse,
+            "tags": statement.read::<String, _>("TAGS").unwrap().split_whitespace().collect::<Vec<&str>>(),
+            "note": statement.read::<i64, _>("NOTE").unwrap(),
+            "template": statement.read::<i64, _>("TEMPLATE").unwrap(),
+            "suspended": statement.read::<i64, _>("SUSPENDED").unwrap() != 0,
+            "buried_until": statement.read::<Option<String>, _>("BURIED_UNTIL").unwrap(),
+            "added": statement.read::<Option<String>, _>("ADDED").unwrap(),
+            "first_study": statement.read::<Option<String>, _>("FIRST_STUDY").unwrap(),
+            "state": Self::state_number(card.state),
+            "due": card.due.to_rfc3339(),
+            "stability": card.stability,
+            "difficulty": card.difficulty,
+            "reps": card.reps,
+            "lapses": card.lapses,
+            "retrievability": Self::retrievability(&card, Utc::now()),
+            "average_time": if durations.is_empty() { None } else { Some(durations.iter().sum::<i64>() as f64 / durations.len() as f64) },
+            "reviews": reviews
+        }))
+    }
+
+    /// Creates a card with a Basic note, returning the id of the card and any cards it duplicates.
+    pub fn new_card(&self, deck: String, obverse: String, reverse: String) -> Result<(i64, Vec<(String, i64)>), ()> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        let (note, id) = Self::create_card(&connection, &deck, obverse, reverse).ok_or(())?;
+        self.refresh();
+        Ok((id, Self::note_duplicates(&connection, note)))
+    }
+
+    /// Creates a Basic note and its cards, returning the note and the id of its first card.
+    fn create_card(connection: &Connection, deck: &str, obverse: String, reverse: String) -> Option<(i64, i64)> {
+        let note = notes::insert_note(connection, notes::BASIC, &[obverse, reverse]);
+        notes::sync_note(connection, note, Some(deck)).first().map(|card| (note, card.1))
+    }
+
+    /// Inserts a card with a fresh FSRS state. This doesn't create a note, so it should only be used for notes' cards.
+    pub fn insert_card(connection: &Connection, deck: &str, obverse: String, reverse: String, note: i64, template: i64, reversed: bool) -> i64 {
+        let card_json = to_json(Card::new());
         let mut statement = connection.prepare(
-            format!("INSERT INTO \"{}\" (OBVERSE, REVERSE, DUE, STABILITY, DIFFICULTY, ELAPSED_DAYS, SCHEDULED_DAYS, REPS, LAPSES, STATE, LAST_REVIEW, PREVIOUS_STATE) VALUES (\"{}\", \"{}\", '{}', {}, {}, {}, {}, {}, {}, {}, '{}', {}) RETURNING ROWID",
+            format!("INSERT INTO \"{}\" (OBVERSE, REVERSE, DUE, STABILITY, DIFFICULTY, ELAPSED_DAYS, SCHEDULED_DAYS, REPS, LAPSES, STATE, LAST_REVIEW, PREVIOUS_STATE, NOTE, TEMPLATE, REVERSED, GUID, ADDED) VALUES ('{}', '{}', '{}', {}, {}, {}, {}, {}, {}, {}, '{}', {}, {}, {}, {}, '{}', '{}') RETURNING ROWID",
                     deck.replace(r#"""#, r#""""#),
-                    obverse.replace(r#"""#, r#""""#),
-                    reverse.replace(r#"""#, r#""""#),
-                    card_json["due"].as_str().unwrap().to_string(),
+                    obverse.replace('\'', "''"),
+                    reverse.replace('\'', "''"),
+                    card_json["due"].as_str().unwrap(),
                     card_json["stability"],
                     card_json["difficulty"],
                     card_json["elapsed_days"],
@@ -280,30 +964,102 @@ impl Cards {
                     card_json["reps"],
                     card_json["lapses"],
                     card_json["state"],
-                    card_json["last_review"].as_str().unwrap().to_string(),
-                    card_json["previous_state"]
+                    card_json["last_review"].as_str().unwrap(),
+                    card_json["previous_state"],
+                    note,
+                    template,
+                    reversed as i64,
+                    Self::new_guid(),
+                    Utc::now().to_rfc3339()
             )
         ).unwrap();
         statement.next().unwrap();
+        let id = statement.read::<i64, _>("rowid").unwrap();
+        Self::touch(connection, deck);
+
+        id
+    }
+
+    /// Sets the fields of a card's Basic note, returning any cards it duplicates. Cards of other note types have to be edited
+    /// through `edit_note`.
+    pub fn edit_card(&self, deck: String, id: i64, obverse: String, reverse: String) -> Result<Vec<(String, i64)>, ()> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        let note = Self::set_content(&connection, &deck, id, obverse, reverse)?;
+        self.refresh();
+        Ok(Self::note_duplicates(&connection, note))
+    }
+
+    /// Sets the fields of a card's Basic note from its sides, returning the note.
+    fn set_content(connection: &Connection, deck: &str, id: i64, obverse: String, reverse: String) -> Result<i64, ()> {
+        let (note, note_type) = notes::card_note(connection, deck, id);
+        if note_type != notes::BASIC { return Err(()); }
+        let mut statement = connection.prepare(format!("SELECT REVERSED FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        statement.next().unwrap();
+        if statement.read::<i64, _>("REVERSED").unwrap() != 0 { notes::set_fields(connection, note, &[reverse, obverse]); }
+        else { notes::set_fields(connection, note, &[obverse, reverse]); }
+        notes::sync_note(connection, note, None);
+        Ok(note)
+    }
+
+    /// Creates a note and its cards, returning the id of the note.
+    pub fn new_note(&self, deck: String, note_type: i64, fields: Vec<String>) -> i64 {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        let note = notes::insert_note(&connection, note_type, &fields);
+        notes::sync_note(&connection, note, Some(&deck));
+        self.refresh();
+        note
+    }
+
+    /// Sets the fields of a note and updates every card made from it, returning any cards it duplicates.
+    pub fn edit_note(&self, id: i64, fields: Vec<String>) -> Vec<(String, i64)> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        notes::set_fields(&connection, id, &fields);
+        notes::sync_note(&connection, id, None);
         self.refresh();
-        Ok(statement.read::<i64, _>("rowid").unwrap())
+        Self::note_duplicates(&connection, id)
     }
 
-    pub fn edit_card(&self, deck: String, id: i64, obverse: String, reverse: String) {
-        Connection::open(Self::get_path()).unwrap().execute(format!("UPDATE \"{}\" SET OBVERSE = \"{}\", REVERSE = \"{}\" WHERE ROWID = {}",
+    pub fn delete_note(&self, id: i64) {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        for (deck, card, _, _) in notes::note_cards(&connection, id) {
+            connection.execute(format!("DELETE FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), card)).unwrap();
+            Self::touch(&connection, &deck);
+        }
+        notes::clean(&connection);
+        reviews::clean(&connection);
+        self.refresh();
+    }
+
+    pub fn new_note_type(name: String, fields: Vec<String>, templates: Vec<notes::Template>, cloze: bool) -> Result<i64, String> { notes::new_note_type(&Connection::open(Self::get_path()).unwrap(), name, fields, templates, cloze) }
+
+    pub fn edit_note_type(&self, id: i64, name: String, fields: Vec<String>, templates: Vec<notes::Template>) -> Result<(), String> {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute("BEGIN").unwrap();
+        if let Err(error) = notes::edit_note_type(&connection, id, name, fields, templates) {
+            connection.execute("ROLLBACK").unwrap();
+            return Err(error);
+        }
+        connection.execute("COMMIT").unwrap();
+        self.refresh();
+        Ok(())
+    }
+
+    /// Tags are stored space separated, so whitespace in `tags` separates them.
+    pub fn edit_tags(&self, deck: String, id: i64, tags: Vec<String>) {
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute(format!("UPDATE \"{}\" SET TAGS = '{}' WHERE ROWID = {}",
             deck.replace(r#"""#, r#""""#),
-            obverse.replace(r#"""#, r#""""#),
-            reverse.replace(r#"""#, r#""""#),
+            tags.join(" ").split_whitespace().collect::<Vec<&str>>().join(" ").replace('\'', "''"),
             id
         )).unwrap();
-        self.refresh();
+        Self::touch(&connection, &deck);
     }
 
     pub fn reset_card(&self, deck: String, id: i64) {
         let card = to_json(Card::new());
         Connection::open(Self::get_path()).unwrap().execute(format!("UPDATE \"{}\" SET DUE = '{}', STABILITY = {}, DIFFICULTY = {}, ELAPSED_DAYS = {}, SCHEDULED_DAYS = {}, REPS = {}, LAPSES = {}, STATE = {}, LAST_REVIEW = '{}', PREVIOUS_STATE = {}, LOG_RATING = {}, LOG_ELAPSED_DAYS = {}, LOG_SCHEDULED_DAYS = {}, LOG_STATE = {}, LOG_REVIEWED_DATE = '{}', FIRST_STUDY = NULL WHERE ROWID = {}",
             deck.replace(r#"""#, r#""""#),
-            card["due"].as_str().unwrap().to_string(),
+            card["due"].as_str().unwrap(),
             card["stability"],
             card["difficulty"],
             card["elapsed_days"],
@@ -311,7 +1067,7 @@ impl Cards {
             card["reps"],
             card["lapses"],
             card["state"],
-            card["last_review"].as_str().unwrap().to_string(),
+            card["last_review"].as_str().unwrap(),
             card["previous_state"],
             card["log"]["rating"],
             card["log"]["elapsed_days"],
@@ -323,8 +1079,104 @@ impl Cards {
         self.refresh();
     }
 
+    /// Deletes a card and its reversed sibling, along with its note if the note has no other cards.
     pub fn delete_card(&self, deck: String, id: i64) {
-        Connection::open(Self::get_path()).unwrap().execute(format!("DELETE FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute(format!("DELETE FROM \"{0}\" WHERE ROWID IN (SELECT SIBLING.ROWID FROM \"{0}\" AS CARD JOIN \"{0}\" AS SIBLING ON SIBLING.NOTE = CARD.NOTE AND SIBLING.TEMPLATE = CARD.TEMPLATE WHERE CARD.ROWID = {1})", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        Self::touch(&connection, &deck);
+        notes::clean(&connection);
+        reviews::clean(&connection);
         self.refresh();
     }
-}
+
+    /// Suspended cards are kept out of the queue until they are unsuspended.
+    pub fn suspend_cards(&self, deck: String, ids: Vec<i64>, suspend: bool) {
+        Connection::open(Self::get_path()).unwrap().execute(format!("UPDATE \"{}\" SET SUSPENDED = {} WHERE ROWID IN ({})",
+            deck.replace(r#"""#, r#""""#),
+            suspend as i64,
+            ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+        )).unwrap();
+        self.refresh();
+    }
+
+    /// Buried cards are kept out of the queue until the start of the next day.
+    pub fn bury_cards(&self, deck: String, ids: Vec<i64>, bury: bool) {
+        Connection::open(Self::get_path()).unwrap().execute(format!("UPDATE \"{}\" SET BURIED_UNTIL = {} WHERE ROWID IN ({})",
+            deck.replace(r#"""#, r#""""#),
+            if bury { format!("'{}'", (Self::day_start() + chrono::Duration::days(1)).to_rfc3339()) } else { "NULL".to_string() },
+            ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+        )).unwrap();
+        self.refresh();
+    }
+
+    /// Moves cards to another deck along with their reversed siblings, keeping their progress. The moved cards get new ids in
+    /// the deck they are moved to.
+    pub fn move_cards(&self, from: String, to: String, ids: Vec<i64>) -> Result<(), ()> {
+        if from == to { return Ok(()); }
+        let connection = Connection::open(Self::get_path()).unwrap();
+        let columns = COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", ");
+        let selection = format!("ROWID IN (SELECT SIBLING.ROWID FROM \"{0}\" AS CARD JOIN \"{0}\" AS SIBLING ON SIBLING.NOTE = CARD.NOTE AND SIBLING.TEMPLATE = CARD.TEMPLATE WHERE CARD.ROWID IN ({1}))",
+            from.replace(r#"""#, r#""""#),
+            ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+        );
+
+        let mut notes: Vec<i64> = Vec::new();
+        {
+            let mut statement = connection.prepare(format!("SELECT DISTINCT NOTE FROM \"{}\" WHERE {}", from.replace(r#"""#, r#""""#), selection)).map_err(|_| ())?;
+            while let Ok(DBState::Row) = statement.next() { notes.push(statement.read::<i64, _>("NOTE").unwrap()); }
+        }
+
+        connection.execute("BEGIN").unwrap();
+        let moved = connection.execute(format!("INSERT INTO \"{}\" ({}) SELECT {} FROM \"{}\" WHERE {}", to.replace(r#"""#, r#""""#), columns, columns, from.replace(r#"""#, r#""""#), selection))
+            .and_then(|_| connection.execute(format!("DELETE FROM \"{}\" WHERE {}", from.replace(r#"""#, r#""""#), selection)));
+        if moved.is_err() {
+            connection.execute("ROLLBACK").unwrap();
+            return Err(());
+        }
+        for note in notes { notes::sync_note(&connection, note, None); }
+        Self::touch(&connection, &from);
+        Self::touch(&connection, &to);
+        connection.execute("COMMIT").unwrap();
+
+        self.refresh();
+        Ok(())
+    }
+
+    /// Replaces text in the notes of every card matching `query`, returning the changed notes as (note, before, after).
+    ///
+    /// The obverse `target` is the first field of a note and the reverse is every other field, which for Basic notes are the
+    /// two sides of the card. `find` is a regular expression if `regex` is set, in which case `replace` can refer to its
+    /// groups with `$1` and so on. Nothing is changed if `dry_run` is set, so the result can be used as a preview.
+    pub fn find_replace(&self, query: String, find: String, replace: String, regex: bool, target: String, dry_run: bool) -> Result<Vec<FieldChange>, String> {
+        let pattern = match regex {
+            true => Regex::new(&find).map_err(|error| error.to_string())?,
+            false => Regex::new(&regex::escape(&find)).unwrap()
+        };
+        let replace = if regex { replace } else { replace.replace('$', "$$") };
+
+        let mut notes: Vec<i64> = search::search(&query).iter().map(|card| card.5["note"].as_i64().unwrap()).collect();
+        notes.sort();
+        notes.dedup();
+        let mut changes: Vec<FieldChange> = Vec::new();
+        for note in notes {
+            let (_, before) = notes::get_note(note);
+            let after: Vec<String> = before.iter().enumerate().map(|(field, value)| match (target.as_str(), field) {
+                ("obverse", 0) | ("reverse", 1..) | ("both", _) => pattern.replace_all(value, replace.as_str()).to_string(),
+                _ => value.clone()
+            }).collect();
+            if after != before { changes.push((note, before, after)); }
+        }
+        if dry_run { return Ok(changes); }
+
+        let connection = Connection::open(Self::get_path()).unwrap();
+        connection.execute("BEGIN").unwrap();
+        for (note, _, after) in &changes {
+            notes::set_fields(&connection, *note, after);
+            notes::sync_note(&connection, *note, None);
+        }
+        connection.execute("COMMIT").unwrap();
+
+        self.refresh();
+        Ok(changes)
+    }
+}
\ No newline at end of file
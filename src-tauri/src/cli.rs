@@ -0,0 +1,129 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use crate::cards::{Cards, ExportOptions};
+use crate::reviews::{self, HistoryOptions};
+use crate::stats::{self, ForecastOptions, StatsOptions};
+
+const USAGE: &str = "usage: srs export <deck> <path> [--format <format>] [--scheduling]
+       srs reviews <path> [--deck <deck>] [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>] [--format csv|jsonl]
+       srs stats [--deck <deck>] [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>]
+       srs forecast [--deck <deck>] [--days <days>] [--new]";
+
+/// Runs a command given on the command line instead of opening the window, returning the exit code, or `None` if the first
+/// argument isn't a command, so that arguments the OS passes when launching the app, like a file to open, still open it.
+///
+/// `export` takes the same options as the `export_deck` command and `reviews` those of `export_reviews`, with the format
+/// taken from the path unless `--format` is given. `stats` and `forecast` print the results of `get_statistics` and
+/// `get_forecast` as JSON.
+pub fn run(args: &[String]) -> Option<i32> {
+    let (command, args) = args.split_first()?;
+    let command: fn(&[String]) -> Result<(), String> = match command.as_str() {
+        "export" => export,
+        "reviews" => export_reviews,
+        "stats" => statistics,
+        "forecast" => forecast,
+        _ => return None
+    };
+
+    attach_console();
+    match command(args) {
+        Ok(_) => Some(0),
+        Err(error) => {
+            eprintln!("{}", error);
+            Some(1)
+        }
+    }
+}
+
+/// Release builds on Windows have no console of their own, so commands attach to the one they were run from to print to
+/// it.
+#[cfg(windows)]
+fn attach_console() {
+    #[link(name = "kernel32")]
+    extern "system" { fn AttachConsole(process_id: u32) -> i32; }
+    // ATTACH_PARENT_PROCESS
+    unsafe { AttachConsole(u32::MAX); }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+fn export(args: &[String]) -> Result<(), String> {
+    let mut positional: Vec<String> = Vec::new();
+    let mut options = ExportOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => options.format = Some(args.next().ok_or(USAGE)?.to_lowercase()),
+            "--scheduling" => options.scheduling = true,
+            _ => positional.push(arg.clone())
+        }
+    }
+    let [deck, path] = <[String; 2]>::try_from(positional).map_err(|_| USAGE)?;
+
+    Cards::migrate();
+    if !Cards::get_decks().contains(&deck) { return Err(format!("There is no deck named {}", deck)); }
+    Cards::export_deck(deck, path, options)
+}
+
+fn export_reviews(args: &[String]) -> Result<(), String> {
+    let mut positional: Vec<String> = Vec::new();
+    let mut options = HistoryOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--deck" => options.deck = Some(args.next().ok_or(USAGE)?.clone()),
+            "--from" => options.from = Some(args.next().ok_or(USAGE)?.clone()),
+            "--to" => options.to = Some(args.next().ok_or(USAGE)?.clone()),
+            "--format" => options.format = Some(args.next().ok_or(USAGE)?.to_lowercase()),
+            _ => positional.push(arg.clone())
+        }
+    }
+    let [path] = <[String; 1]>::try_from(positional).map_err(|_| USAGE)?;
+
+    Cards::migrate();
+    reviews::export(&path, options)
+}
+
+fn statistics(args: &[String]) -> Result<(), String> {
+    let mut options = StatsOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--deck" => options.deck = Some(args.next().ok_or(USAGE)?.clone()),
+            "--from" => options.from = Some(args.next().ok_or(USAGE)?.clone()),
+            "--to" => options.to = Some(args.next().ok_or(USAGE)?.clone()),
+            _ => return Err(USAGE.to_string())
+        }
+    }
+
+    Cards::migrate();
+    println!("{}", serde_json::to_string_pretty(&stats::statistics(options)?).unwrap());
+    Ok(())
+}
+
+fn forecast(args: &[String]) -> Result<(), String> {
+    let mut options = ForecastOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--deck" => options.deck = Some(args.next().ok_or(USAGE)?.clone()),
+            "--days" => options.days = args.next().ok_or(USAGE)?.parse().map_err(|_| USAGE)?,
+            "--new" => options.new = true,
+            _ => return Err(USAGE.to_string())
+        }
+    }
+
+    Cards::migrate();
+    println!("{}", serde_json::to_string_pretty(&stats::forecast(options)?).unwrap());
+    Ok(())
+}
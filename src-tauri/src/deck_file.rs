@@ -0,0 +1,239 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use chrono::Utc;
+use fsrs::Card;
+use serde_json::{json, Value};
+use sqlite::{Connection, State as DBState, Value as DBValue};
+use std::collections::HashMap;
+use std::fs;
+use crate::cards::{Cards, ImportReport, COLUMNS, DECKS};
+use crate::{notes, reviews};
+
+/// The version of the srs deck format that `export` writes. Files from later versions are refused, since they may hold
+/// something that would be lost.
+pub const VERSION: i64 = 1;
+
+/// Exports a deck with everything needed to restore it on another machine: its options, the notes and note types of its
+/// cards, and every column of each card, including its FSRS state, along with its review history.
+///
+/// The file is a JSON object with `format` set to `"srs"` and `version` set to `VERSION`. Cards have a key for each column
+/// of a deck in lowercase and a `reviews` array, and refer to notes by their `id` in the file.
+pub fn export(deck: &str, path: &str) -> Result<(), String> {
+    fs::write(path, deck_json(deck, true)?.to_string()).map_err(|error| error.to_string())
+}
+
+/// A deck in the form `export` writes. Without `progress`, every card is written as new and without its review history,
+/// for sharing a deck with others.
+pub fn deck_json(deck: &str, progress: bool) -> Result<Value, String> {
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let mut cards: Vec<serde_json::Map<String, Value>> = Vec::new();
+    let mut statement = connection.prepare(format!("SELECT * FROM \"{}\"", deck.replace(r#"""#, r#""""#))).map_err(|error| error.to_string())?;
+    while let Ok(DBState::Row) = statement.next() {
+        let mut card = serde_json::Map::new();
+        for (name, _) in COLUMNS {
+            card.insert(name.to_lowercase(), match statement.read::<DBValue, _>(name).unwrap() {
+                DBValue::Integer(value) => json!(value),
+                DBValue::Float(value) => json!(value),
+                DBValue::String(value) => json!(value),
+                _ => Value::Null
+            });
+        }
+        cards.push(card);
+    }
+    for card in cards.iter_mut() {
+        let history = match progress {
+            true => reviews::card_reviews(&connection, card["guid"].as_str().unwrap()),
+            false => {
+                reset(card);
+                Vec::new()
+            }
+        };
+        card.insert("reviews".to_string(), json!(history));
+    }
+
+    let mut note_ids: Vec<i64> = cards.iter().filter_map(|card| card["note"].as_i64()).collect();
+    note_ids.sort();
+    note_ids.dedup();
+    let notes: Vec<(i64, i64, Vec<String>)> = note_ids.into_iter().map(|id| {
+        let (note_type, fields) = notes::read_note(&connection, id);
+        (id, note_type, fields)
+    }).collect();
+    let mut note_type_ids: Vec<i64> = notes.iter().map(|note| note.1).collect();
+    note_type_ids.sort();
+    note_type_ids.dedup();
+
+    Ok(json!({
+        "format": "srs",
+        "version": VERSION,
+        "name": deck,
+        "options": Cards::get_deck_options_with(&connection, deck),
+        "metadata": Cards::deck_metadata(&connection, deck),
+        "note_types": note_type_ids.into_iter().map(|id| {
+            let (id, name, fields, templates, cloze) = notes::get_note_type(&connection, id);
+            json!({"id": id, "name": name, "fields": fields, "templates": templates, "cloze": cloze})
+        }).collect::<Vec<Value>>(),
+        "notes": notes.into_iter().map(|(id, note_type, fields)| json!({"id": id, "note_type": note_type, "fields": fields})).collect::<Vec<Value>>(),
+        "cards": cards
+    }))
+}
+
+/// Replaces a card's progress with the state of a new card.
+fn reset(card: &mut serde_json::Map<String, Value>) {
+    let fresh = Card::new();
+    for (name, value) in [
+        ("due", json!(fresh.due.to_rfc3339())),
+        ("stability", json!(fresh.stability)),
+        ("difficulty", json!(fresh.difficulty)),
+        ("elapsed_days", json!(fresh.elapsed_days)),
+        ("scheduled_days", json!(fresh.scheduled_days)),
+        ("reps", json!(fresh.reps)),
+        ("lapses", json!(fresh.lapses)),
+        ("state", json!(Cards::state_number(fresh.state))),
+        ("last_review", json!(fresh.last_review.to_rfc3339())),
+        ("previous_state", json!(Cards::state_number(fresh.previous_state))),
+        ("suspended", json!(0))
+    ] { card.insert(name.to_string(), value); }
+    for name in ["log_rating", "log_elapsed_days", "log_scheduled_days", "log_state", "log_reviewed_date", "first_study", "buried_until", "added"] {
+        card.insert(name.to_string(), Value::Null);
+    }
+}
+
+/// Imports a deck exported by `export` as a new deck, restoring each card exactly as it was along with the deck's options
+/// and metadata.
+///
+/// Note types are reused if an identical one exists. Cards keep their GUIDs, unless a card in the collection already has the
+/// same one, such as when a deck is imported twice. The caller runs this inside a transaction, so that nothing is imported
+/// if any part fails.
+///
+/// If `into` names a deck, the file is merged into it instead: cards with the GUID of a card in the deck update that card's
+/// note and keep its progress, and other cards are added. Cards without a GUID, like those of older or hand-written files,
+/// are matched by their normalized obverse instead, as `import_deck` matches cards. Matched cards whose note type differs
+/// from the existing note's are left as they are and counted as skipped. With `remove`, cards in the deck that aren't in
+/// the file are deleted.
+pub fn import(connection: &Connection, path: &str, into: Option<&str>, remove: bool) -> Result<ImportReport, String> {
+    import_value(connection, &read(path)?, into, remove)
+}
+
+/// Reads a deck file without importing it.
+pub fn read(path: &str) -> Result<Value, String> {
+    serde_json::from_str(&fs::read_to_string(path).map_err(|error| error.to_string())?).map_err(|error| error.to_string())
+}
+
+/// Imports a deck in the form `deck_json` returns, as `import` describes.
+pub fn import_value(connection: &Connection, file: &Value, into: Option<&str>, remove: bool) -> Result<ImportReport, String> {
+    if file["format"] != "srs" { return Err("This isn't an srs deck".to_string()); }
+    let version = file["version"].as_i64().unwrap_or_default();
+    if version > VERSION { return Err(format!("This deck is from a newer version of srs (format version {})", version)); }
+
+    let deck = match into {
+        Some(deck) => deck.to_string(),
+        None => Cards::create_deck(connection, file["name"].as_str().ok_or("The deck has no name")?)?
+    };
+    if into.is_none() {
+        if let Some(reverse) = file["options"]["reverse"].as_bool() {
+            connection.execute(format!("UPDATE {} SET REVERSE = {} WHERE NAME = '{}'", DECKS, reverse as i64, deck.replace('\'', "''"))).unwrap();
+        }
+        if let Some(leech_threshold) = file["options"]["leech_threshold"].as_i64() {
+            connection.execute(format!("UPDATE {} SET LEECH_THRESHOLD = {} WHERE NAME = '{}'", DECKS, leech_threshold.max(0), deck.replace('\'', "''"))).unwrap();
+        }
+        Cards::write_deck_metadata(connection, &deck, &file["metadata"]);
+        if let Some(created) = file["metadata"]["created"].as_str() {
+            connection.execute(format!("UPDATE {} SET CREATED = '{}' WHERE NAME = '{}'", DECKS, created.replace('\'', "''"), deck.replace('\'', "''"))).unwrap();
+        }
+    }
+
+    let mut note_types: HashMap<i64, i64> = HashMap::new();
+    for note_type in file["note_types"].as_array().ok_or("The deck has no note types")? {
+        note_types.insert(note_type["id"].as_i64().ok_or("A note type has no id")?, notes::find_note_type(
+            connection,
+            note_type["name"].as_str().unwrap_or_default().to_string(),
+            serde_json::from_value(note_type["fields"].clone()).map_err(|error| error.to_string())?,
+            serde_json::from_value(note_type["templates"].clone()).map_err(|error| error.to_string())?,
+            note_type["cloze"].as_bool().unwrap_or(false)
+        )?);
+    }
+
+    // Notes whose cards are all merged into existing ones are left without cards, and removed at the end.
+    let mut note_ids: HashMap<i64, (i64, i64, Vec<String>)> = HashMap::new();
+    for note in file["notes"].as_array().ok_or("The deck has no notes")? {
+        let note_type = note_types.get(&note["note_type"].as_i64().unwrap_or_default()).ok_or("A note has a missing note type")?;
+        let fields: Vec<String> = serde_json::from_value(note["fields"].clone()).map_err(|error| error.to_string())?;
+        note_ids.insert(note["id"].as_i64().ok_or("A note has no id")?, (notes::insert_note(connection, *note_type, &fields), *note_type, fields));
+    }
+
+    let mut by_obverse: HashMap<(String, bool), i64> = HashMap::new();
+    if into.is_some() {
+        let mut statement = connection.prepare(format!("SELECT ROWID, OBVERSE, REVERSED FROM \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            let obverse = Cards::normalize(&statement.read::<String, _>("OBVERSE").unwrap());
+            if obverse.is_empty() { continue; }
+            by_obverse.entry((obverse, statement.read::<i64, _>("REVERSED").unwrap() != 0)).or_insert(statement.read::<i64, _>("rowid").unwrap());
+        }
+    }
+
+    let mut kept: Vec<i64> = Vec::new();
+    let mut report = ImportReport::default();
+    for card in file["cards"].as_array().ok_or("The deck has no cards")? {
+        let (note, note_type, fields) = note_ids.get(&card["note"].as_i64().unwrap_or_default()).ok_or("A card has a missing note")?;
+        let matched = match card["guid"].as_str() {
+            Some(guid) => into.and_then(|_| Cards::deck_guid(connection, &deck, guid)),
+            None => by_obverse.get(&(Cards::normalize(card["obverse"].as_str().unwrap_or_default()), card["reversed"] == true || card["reversed"] == 1)).copied()
+        };
+        if let Some(id) = matched {
+            let (existing, existing_type) = notes::card_note(connection, &deck, id);
+            // The fields of a different note type don't line up with the existing note's, so the card is left as it is.
+            if existing_type == *note_type {
+                notes::set_fields(connection, existing, fields);
+                notes::sync_note(connection, existing, None);
+                report.updated += 1;
+            } else { report.skipped += 1; }
+            kept.push(id);
+            continue;
+        }
+
+        let guid = match card["guid"].as_str() {
+            Some(guid) if Cards::find_guid(connection, guid).is_none() => guid.to_string(),
+            _ => Cards::new_guid()
+        };
+        let mut columns: Vec<&str> = Vec::new();
+        let mut values: Vec<String> = Vec::new();
+        for (name, _) in COLUMNS {
+            let value = match name {
+                "NOTE" => note.to_string(),
+                "GUID" => format!("'{}'", guid.replace('\'', "''")),
+                // Cards from older files, or shared without progress, count as added when they're imported.
+                "ADDED" if !card["added"].is_string() => format!("'{}'", Utc::now().to_rfc3339()),
+                _ => match &card[name.to_lowercase()] {
+                    Value::String(value) => format!("'{}'", value.replace('\'', "''")),
+                    Value::Number(value) => value.to_string(),
+                    Value::Bool(value) => (*value as i64).to_string(),
+                    _ => continue
+                }
+            };
+            columns.push(name);
+            values.push(value);
+        }
+        let mut statement = connection.prepare(format!("INSERT INTO \"{}\" ({}) VALUES ({}) RETURNING ROWID", deck.replace(r#"""#, r#""""#), columns.join(", "), values.join(", "))).map_err(|error| error.to_string())?;
+        statement.next().map_err(|error| error.to_string())?;
+        kept.push(statement.read::<i64, _>("rowid").unwrap());
+        report.added += 1;
+        reviews::insert(connection, &guid, card["reviews"].as_array().map(Vec::as_slice).unwrap_or_default());
+    }
+    if into.is_some() && remove { report.removed = Cards::remove_absent(connection, &deck, &kept); }
+    // Files from decks without reverse cards have no reversed siblings, which a deck with them enabled still needs.
+    if Cards::get_deck_options_with(connection, &deck)["reverse"] == true {
+        for (note, _, _) in note_ids.values() { notes::sync_note(connection, *note, None); }
+    }
+    notes::clean(connection);
+
+    Ok(report)
+}
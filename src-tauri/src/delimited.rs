@@ -0,0 +1,125 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use csv::{ReaderBuilder, WriterBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use crate::cards::Cards;
+use crate::notes;
+
+/// How the columns of a CSV or TSV file become cards.
+///
+/// `obverse` and `reverse` are templates in the same syntax as note templates, where columns are referred to either by
+/// their number starting from 1 (`{{1}}`) or by their header (`{{Word}}`), so several columns can be combined into one
+/// side. The values of the `tags` columns are split on whitespace and added as tags, and the `guid` column identifies cards
+/// when merging into an existing deck.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Options {
+    /// Detected from the first line if not set.
+    pub delimiter: Option<char>,
+    /// If not set, the first row is a header only if the templates refer to a column by a name in it, so a header row above
+    /// columns referred to by number has to be set to be skipped.
+    pub header: Option<bool>,
+    pub obverse: String,
+    pub reverse: String,
+    pub tags: Vec<usize>,
+    pub guid: Option<usize>
+}
+
+impl Default for Options {
+    fn default() -> Options { Options { delimiter: None, header: None, obverse: "{{1}}".to_string(), reverse: "{{2}}".to_string(), tags: Vec::new(), guid: None } }
+}
+
+/// A card read from a file, as (obverse, reverse, tags, GUID).
+pub type Row = (String, String, Vec<String>, Option<String>);
+
+/// Reads the cards in a CSV or TSV file.
+pub fn read(path: &str, options: &Options) -> Result<Vec<Row>, String> {
+    let content = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let content = content.trim_start_matches('\u{feff}');
+    let first_line = content.lines().next().unwrap_or_default();
+    let delimiter = options.delimiter.unwrap_or_else(|| detect_delimiter(path, first_line));
+    // Files where no field starts with a quote are read without quoting, so that quotes inside of text don't swallow the
+    // delimiters after them.
+    let quoting = content.lines().take(100).any(|line| line.split(delimiter).any(|field| field.trim_start().starts_with('"')));
+
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for record in ReaderBuilder::new().delimiter(delimiter as u8).quoting(quoting).has_headers(false).flexible(true).from_reader(content.as_bytes()).records() {
+        rows.push(record.map_err(|error| error.to_string())?.iter().map(|field| field.to_string()).collect());
+    }
+
+    let header = match options.header {
+        Some(header) => header,
+        None => rows.first().map(|row| row.iter().any(|name| {
+            let reference = format!("{{{{{}}}}}", name.trim());
+            !name.trim().is_empty() && (options.obverse.contains(&reference) || options.reverse.contains(&reference))
+        })).unwrap_or(false)
+    };
+    let names: Vec<String> = match header {
+        true => rows.remove(0).iter().map(|name| name.trim().to_string()).collect(),
+        false => Vec::new()
+    };
+
+    let mut cards: Vec<Row> = Vec::new();
+    for row in rows.into_iter().filter(|row| row.iter().any(|field| !field.trim().is_empty())) {
+        let mut fields: Vec<String> = (1..=row.len()).map(|column| column.to_string()).collect();
+        let mut values = row.clone();
+        for (name, value) in names.iter().zip(&row) {
+            fields.push(name.clone());
+            values.push(value.clone());
+        }
+        cards.push((
+            notes::render(&options.obverse, &fields, &values),
+            notes::render(&options.reverse, &fields, &values),
+            options.tags.iter().filter_map(|column| row.get(column.wrapping_sub(1))).flat_map(|tags| tags.split_whitespace().map(str::to_string)).collect(),
+            options.guid.and_then(|column| row.get(column.wrapping_sub(1))).map(|guid| guid.trim().to_string()).filter(|guid| !guid.is_empty())
+        ));
+    }
+
+    Ok(cards)
+}
+
+/// Writes a deck as CSV or TSV with a header row, so that the file can be imported again with `{{obverse}}` and
+/// `{{reverse}}` as templates. With `scheduling`, each card's FSRS state is written in columns after its GUID.
+pub fn write(deck: &str, path: &str, delimiter: u8, scheduling: bool) -> Result<(), String> {
+    let mut writer = WriterBuilder::new().delimiter(delimiter).from_path(path).map_err(|error| error.to_string())?;
+    let mut header = vec!["obverse", "reverse", "tags", "guid"];
+    if scheduling { header.extend(["state", "due", "stability", "difficulty", "reps", "lapses", "last_review", "suspended"]); }
+    writer.write_record(&header).map_err(|error| error.to_string())?;
+
+    for (_, _, obverse, reverse, state, properties) in Cards::get_deck(deck.to_string()) {
+        let mut record = vec![
+            obverse,
+            reverse,
+            properties["tags"].as_array().unwrap().iter().map(|tag| tag.as_str().unwrap()).collect::<Vec<&str>>().join(" "),
+            properties["guid"].as_str().unwrap().to_string()
+        ];
+        if scheduling {
+            record.extend(["state", "due", "stability", "difficulty", "reps", "lapses", "last_review"].into_iter().map(|key| match &state[key] {
+                Value::String(value) => value.clone(),
+                Value::Null => String::default(),
+                value => value.to_string()
+            }));
+            record.push((properties["suspended"] == true).to_string());
+        }
+        writer.write_record(&record).map_err(|error| error.to_string())?;
+    }
+
+    writer.flush().map_err(|error| error.to_string())
+}
+
+/// TSV files are always tab delimited, while for others the most common delimiter in the first line is used.
+fn detect_delimiter(path: &str, first_line: &str) -> char {
+    if path.to_lowercase().ends_with(".tsv") { return '\t'; }
+    [',', '\t', ';', '|'].into_iter().rev().max_by_key(|delimiter| first_line.matches(*delimiter).count()).unwrap()
+}
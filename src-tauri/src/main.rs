@@ -11,8 +11,20 @@ defined by the Mozilla Public License, v. 2.0.
 
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod anki;
 mod cards;
+mod cli;
+mod deck_file;
+mod delimited;
+mod media;
+mod notes;
+mod package;
+mod reviews;
+mod search;
 mod settings;
+mod stats;
+mod text;
+mod update;
 
 use chrono::Utc;
 use fsrs::{FSRS, Rating};
@@ -20,15 +32,22 @@ use serde_json::{json, Value};
 use tauri::State;
 
 fn main() {
+    if let Some(code) = cli::run(&std::env::args().skip(1).collect::<Vec<String>>()) { std::process::exit(code); }
+
     tauri::Builder::default()
         .manage(settings::Settings::default())
         .manage(cards::Cards::default())
+        .register_uri_scheme_protocol(media::PROTOCOL, |_, request| media::serve(request))
         .invoke_handler(tauri::generate_handler![
             card_count,
             next_card, update_card,
             get_settings, set_settings,
-            get_decks, new_deck, import_deck, export_deck, rename_deck, delete_deck,
-            get_deck, new_card, edit_card, reset_card, delete_card,
+            get_decks, new_deck, import_deck, update_deck, export_deck, export_reviews, rename_deck, delete_deck, get_deck_options, set_deck_options, get_deck_metadata, set_deck_metadata,
+            get_deck, get_card_info, new_card, edit_card, edit_tags, reset_card, delete_card, suspend_cards, bury_cards, move_cards,
+            find_duplicates, search_cards, find_replace,
+            get_note_types, new_note_type, edit_note_type, delete_note_type, get_note, new_note, edit_note, delete_note,
+            get_leeches, get_statistics, get_forecast, get_activity,
+            add_media, clean_media,
             exit
         ])
         .run(tauri::generate_context!())
@@ -41,13 +60,13 @@ fn main() {
 fn next_card(cards: State<cards::Cards>) -> Value {
     let card = cards.current_card();
     match card {
-        Ok(_) => json!([card.clone().unwrap().2, card.clone().unwrap().3]),
+        Ok(_) => json!([card.clone().unwrap().2, card.clone().unwrap().3, card.clone().unwrap().0, card.clone().unwrap().1]),
         Err(_) => json!([])
     }
 }
 
 #[tauri::command]
-fn update_card(rating: i8, cards: State<cards::Cards>) {
+fn update_card(rating: i8, duration: Option<i64>, cards: State<cards::Cards>) -> bool {
     let card = cards.current_card().unwrap();
     cards.update_card(card.clone().0, card.clone().1, &FSRS::default().schedule(card.clone().4, Utc::now()).select_card(match rating {
         1 => Rating::Again,
@@ -55,7 +74,7 @@ fn update_card(rating: i8, cards: State<cards::Cards>) {
         3 => Rating::Good,
         4 => Rating::Easy,
         _ => panic!()
-    }));
+    }), duration)
 }
 
 #[tauri::command] fn get_settings (settings: State<settings::Settings>) -> Value { settings.get() }
@@ -66,17 +85,53 @@ fn set_settings(settings: State<settings::Settings>, value: Value) {
     settings.save();
 }
 
-#[tauri::command] fn get_decks() -> Vec<String> { cards::Cards::get_decks() }
+#[tauri::command] fn get_decks() -> Vec<(String, Value)> { cards::Cards::get_decks_with_metadata() }
 #[tauri::command] fn new_deck(deck: String) { cards::Cards::new_deck(deck); }
-#[tauri::command] fn import_deck(path: String, cards: State<cards::Cards>) { cards.import_deck(path).unwrap(); }
-#[tauri::command] fn export_deck(deck: String, path: String) { cards::Cards::export_deck(deck, path); }
+#[tauri::command] fn import_deck(path: String, options: Option<cards::ImportOptions>, cards: State<cards::Cards>) -> Result<cards::ImportReport, Vec<String>> { cards.import_deck(path, options.unwrap_or_default()) }
+#[tauri::command] fn update_deck(deck: String, path: String, options: Option<update::UpdateOptions>, cards: State<cards::Cards>) -> Result<Value, Vec<String>> { cards.update_deck(deck, path, options.unwrap_or_default()) }
+#[tauri::command] fn export_deck(deck: String, path: String, options: Option<cards::ExportOptions>) -> Result<(), String> { cards::Cards::export_deck(deck, path, options.unwrap_or_default()) }
+#[tauri::command] fn export_reviews(path: String, options: Option<reviews::HistoryOptions>) -> Result<(), String> { reviews::export(&path, options.unwrap_or_default()) }
 #[tauri::command] fn rename_deck(deck: String, name: String, cards: State<cards::Cards>) { cards.rename_deck(deck, name); }
 #[tauri::command] fn delete_deck(deck: String, cards: State<cards::Cards>) { cards.delete_deck(deck); }
+#[tauri::command] fn get_deck_options(deck: String) -> Value { cards::Cards::get_deck_options(deck) }
+#[tauri::command] fn set_deck_options(deck: String, options: Value, cards: State<cards::Cards>) { cards.set_deck_options(deck, options); }
+#[tauri::command] fn get_deck_metadata(deck: String) -> Value { cards::Cards::get_deck_metadata(deck) }
+#[tauri::command] fn set_deck_metadata(deck: String, metadata: Value) { cards::Cards::set_deck_metadata(deck, metadata); }
 
-#[tauri::command] fn get_deck(deck: String) -> Vec<(String, i64, String, String, Value)> { cards::Cards::get_deck(deck) }
-#[tauri::command] fn new_card(deck: String, cards: State<cards::Cards>) -> i64 { cards.new_card(deck, String::default(), String::default()).unwrap() }
-#[tauri::command] fn edit_card(deck: String, id: i64, obverse: String, reverse: String, cards: State<cards::Cards>) { cards.edit_card(deck, id, obverse, reverse); }
+#[tauri::command] fn get_deck(deck: String) -> Vec<cards::ListedCard> { cards::Cards::get_deck(deck) }
+#[tauri::command] fn get_card_info(deck: String, id: i64) -> Result<Value, String> { cards::Cards::card_info(deck, id) }
+#[tauri::command] fn new_card(deck: String, cards: State<cards::Cards>) -> (i64, Vec<(String, i64)>) { cards.new_card(deck, String::default(), String::default()).unwrap() }
+#[tauri::command] fn edit_card(deck: String, id: i64, obverse: String, reverse: String, cards: State<cards::Cards>) -> Result<Vec<(String, i64)>, ()> { cards.edit_card(deck, id, obverse, reverse) }
+#[tauri::command] fn edit_tags(deck: String, id: i64, tags: Vec<String>, cards: State<cards::Cards>) { cards.edit_tags(deck, id, tags); }
 #[tauri::command] fn reset_card(deck: String, id: i64, cards: State<cards::Cards>) { cards.reset_card(deck, id); }
 #[tauri::command] fn delete_card(deck: String, id: i64, cards: State<cards::Cards>) { cards.delete_card(deck, id); }
+#[tauri::command] fn suspend_cards(deck: String, ids: Vec<i64>, suspend: bool, cards: State<cards::Cards>) { cards.suspend_cards(deck, ids, suspend); }
+#[tauri::command] fn bury_cards(deck: String, ids: Vec<i64>, bury: bool, cards: State<cards::Cards>) { cards.bury_cards(deck, ids, bury); }
+#[tauri::command] fn move_cards(from: String, to: String, ids: Vec<i64>, cards: State<cards::Cards>) -> Result<(), ()> { cards.move_cards(from, to, ids) }
+
+#[tauri::command] fn find_duplicates(deck: Option<String>) -> Vec<Vec<(String, i64, String)>> { cards::Cards::find_duplicates(deck) }
+#[tauri::command] fn search_cards(query: String) -> Vec<cards::ListedCard> { search::search(&query) }
+
+#[tauri::command]
+fn find_replace(query: String, find: String, replace: String, regex: bool, target: String, dry_run: bool, cards: State<cards::Cards>) -> Result<Vec<cards::FieldChange>, String> {
+    cards.find_replace(query, find, replace, regex, target, dry_run)
+}
+
+#[tauri::command] fn get_note_types() -> Vec<notes::NoteType> { notes::get_note_types() }
+#[tauri::command] fn new_note_type(name: String, fields: Vec<String>, templates: Vec<notes::Template>, cloze: bool) -> Result<i64, String> { cards::Cards::new_note_type(name, fields, templates, cloze) }
+#[tauri::command] fn edit_note_type(id: i64, name: String, fields: Vec<String>, templates: Vec<notes::Template>, cards: State<cards::Cards>) -> Result<(), String> { cards.edit_note_type(id, name, fields, templates) }
+#[tauri::command] fn delete_note_type(id: i64) -> Result<(), ()> { notes::delete_note_type(id) }
+#[tauri::command] fn get_note(id: i64) -> (i64, Vec<String>) { notes::get_note(id) }
+#[tauri::command] fn new_note(deck: String, note_type: i64, fields: Vec<String>, cards: State<cards::Cards>) -> i64 { cards.new_note(deck, note_type, fields) }
+#[tauri::command] fn edit_note(id: i64, fields: Vec<String>, cards: State<cards::Cards>) -> Vec<(String, i64)> { cards.edit_note(id, fields) }
+#[tauri::command] fn delete_note(id: i64, cards: State<cards::Cards>) { cards.delete_note(id); }
+
+#[tauri::command] fn get_leeches() -> Vec<Value> { stats::leeches() }
+#[tauri::command] fn get_statistics(options: Option<stats::StatsOptions>) -> Result<Value, String> { stats::statistics(options.unwrap_or_default()) }
+#[tauri::command] fn get_forecast(options: Option<stats::ForecastOptions>) -> Result<Value, String> { stats::forecast(options.unwrap_or_default()) }
+#[tauri::command] fn get_activity(deck: Option<String>) -> Result<Value, String> { stats::activity(deck) }
+
+#[tauri::command] fn add_media(path: String) -> Result<String, String> { media::add(&path) }
+#[tauri::command] fn clean_media() -> Result<Vec<String>, String> { media::clean() }
 
 #[tauri::command] fn exit() { std::process::exit(0); }
\ No newline at end of file
@@ -0,0 +1,172 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use sqlite::{Connection, State as DBState};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tauri::http::{Request, Response, ResponseBuilder};
+use crate::cards::Cards;
+use crate::notes;
+
+/// The URI scheme media is served to the webview under, which `convertFileSrc` builds URLs for.
+pub const PROTOCOL: &str = "media";
+
+/// Where media files are kept, next to the collection.
+pub fn media_dir() -> PathBuf { Path::new(&Cards::get_path()).parent().unwrap().join("media") }
+
+/// The name a file's content is stored under: its SHA-256 along with its extension, so that a file added twice is only
+/// stored once.
+pub fn name(content: &[u8], extension: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(content));
+    let extension = extension.to_lowercase();
+    if !extension.is_empty() && extension.chars().all(|character| character.is_ascii_alphanumeric()) { format!("{}.{}", hash, extension) }
+    else { hash }
+}
+
+/// Adds content to the media folder, returning the name cards refer to it by.
+pub fn store(content: &[u8], extension: &str) -> Result<String, String> {
+    let name = name(content, extension);
+    fs::create_dir_all(media_dir()).map_err(|error| error.to_string())?;
+    if !media_dir().join(&name).is_file() { fs::write(media_dir().join(&name), content).map_err(|error| error.to_string())?; }
+
+    Ok(name)
+}
+
+/// Adds the file at `path` to the media folder. Cards show it with `![](name)`, as an audio player if it's a sound.
+pub fn add(path: &str) -> Result<String, String> {
+    let content = fs::read(path).map_err(|error| error.to_string())?;
+    store(&content, Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or_default())
+}
+
+/// The names of the media files Markdown refers to.
+pub fn references(text: &str) -> Vec<String> {
+    Regex::new(r"!\[[^\]]*\]\(([^)\s]+)\)").unwrap().captures_iter(text)
+        .filter(|captures| !captures[1].contains(':'))
+        .filter_map(|captures| Path::new(&captures[1].replace("%20", " ")).file_name().map(|name| name.to_string_lossy().to_string()))
+        .collect()
+}
+
+/// Replaces references to media files named in `names` with the names they were stored under.
+pub fn rename(text: &str, names: &HashMap<String, String>) -> String {
+    Regex::new(r"(!\[[^\]]*\]\()([^)\s]+)\)").unwrap().replace_all(text, |captures: &Captures| {
+        match names.get(&captures[2].replace("%20", " ")) {
+            Some(name) => format!("{}{})", &captures[1], name),
+            None => captures[0].to_string()
+        }
+    }).to_string()
+}
+
+/// Counts the references to each media file from notes, note types' templates and cards, including ones to files that are
+/// missing.
+pub fn reference_counts(connection: &Connection) -> HashMap<String, usize> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (table, column) in [(notes::NOTES, "FIELDS"), (notes::NOTE_TYPES, "TEMPLATES")] {
+        let mut statement = connection.prepare(format!("SELECT {} FROM {}", column, table)).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            for name in references(&statement.read::<String, _>(column).unwrap()) { *counts.entry(name).or_default() += 1; }
+        }
+    }
+    // Cards without a note predate notes and hold their content themselves.
+    for deck in Cards::list_decks(connection) {
+        let mut statement = connection.prepare(format!("SELECT OBVERSE, REVERSE FROM \"{}\" WHERE NOTE IS NULL", deck.replace(r#"""#, r#""""#))).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            for column in ["OBVERSE", "REVERSE"] {
+                for name in references(&statement.read::<String, _>(column).unwrap()) { *counts.entry(name).or_default() += 1; }
+            }
+        }
+    }
+
+    counts
+}
+
+/// Deletes the media files that nothing refers to, returning their names. Files added since the collection was last
+/// written to are kept, since they may have been attached to a note that hasn't been saved yet.
+pub fn clean() -> Result<Vec<String>, String> {
+    let counts = reference_counts(&Connection::open(Cards::get_path()).unwrap());
+    let saved = fs::metadata(Cards::get_path()).and_then(|metadata| metadata.modified()).map_err(|error| error.to_string())?;
+    let Ok(entries) = fs::read_dir(media_dir()) else { return Ok(Vec::new()); };
+    let mut removed: Vec<String> = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|error| error.to_string())?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if counts.contains_key(&name) { continue; }
+        if entry.metadata().and_then(|metadata| metadata.modified()).map_or(true, |modified| modified > saved) { continue; }
+        fs::remove_file(media_dir().join(&name)).map_err(|error| error.to_string())?;
+        removed.push(name);
+    }
+
+    Ok(removed)
+}
+
+/// Answers the webview's requests for media. Only files directly in the media folder are served, so that cards can't read
+/// anything else on disk.
+pub fn serve(request: &Request) -> Result<Response, Box<dyn Error>> {
+    // The URL is media://localhost/<name>, or https://media.localhost/<name> on Windows.
+    let name = decode(request.uri().split_once("localhost/").map(|(_, name)| name).unwrap_or_default().split(['?', '#']).next().unwrap_or_default());
+    let mut components = Path::new(&name).components();
+    let file = match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) => media_dir().join(file),
+        _ => return ResponseBuilder::new().status(400).body(Vec::new())
+    };
+    let Ok(content) = fs::read(&file) else { return ResponseBuilder::new().status(404).body(Vec::new()); };
+
+    ResponseBuilder::new()
+        .status(200)
+        .mimetype(mimetype(&file))
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Content-Security-Policy", "default-src 'none'")
+        .body(content)
+}
+
+/// Decodes the percent escapes in a URL path.
+fn decode(path: &str) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Whether a media file is a sound, which cards show as an audio player, from its extension.
+pub fn is_sound(name: &str) -> bool { mimetype(Path::new(name)).starts_with("audio/") }
+
+/// The type of a media file, from its extension. Anything that isn't an image or a sound is served as plain bytes.
+fn mimetype(file: &Path) -> &'static str {
+    match file.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase).as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("bmp") => "image/bmp",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") | Some("oga") | Some("opus") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("m4a") | Some("aac") => "audio/mp4",
+        Some("flac") => "audio/flac",
+        _ => "application/octet-stream"
+    }
+}
@@ -0,0 +1,408 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use serde_json::json;
+use sqlite::{Connection, State as DBState};
+use crate::cards::Cards;
+use crate::reviews;
+
+pub const NOTE_TYPES: &str = "srs_note_types";
+pub const NOTES: &str = "srs_notes";
+
+/// The note type every card created through `new_card` uses, with the fields `Front` and `Back`.
+pub const BASIC: i64 = 1;
+
+/// A cloze note type, with the fields `Text` and `Extra`.
+pub const CLOZE: i64 = 2;
+
+/// A card template, as (name, obverse, reverse).
+pub type Template = (String, String, String);
+
+/// A note type, as (ID, name, fields, templates, whether it's a cloze type).
+pub type NoteType = (i64, String, Vec<String>, Vec<Template>, bool);
+
+/// Creates the note tables and the built in note types if they don't exist yet.
+pub fn migrate(connection: &Connection) {
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (NAME TEXT, FIELDS TEXT, TEMPLATES TEXT, CLOZE INTEGER NOT NULL DEFAULT 0)", NOTE_TYPES)).unwrap();
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (NOTE_TYPE INTEGER, FIELDS TEXT)", NOTES)).unwrap();
+    let mut statement = connection.prepare(format!("SELECT * FROM pragma_table_info('{}') WHERE NAME = 'CLOZE'", NOTE_TYPES)).unwrap();
+    if let Ok(DBState::Done) = statement.next() { connection.execute(format!("ALTER TABLE {} ADD COLUMN CLOZE INTEGER NOT NULL DEFAULT 0", NOTE_TYPES)).unwrap(); }
+    connection.execute(format!("INSERT OR IGNORE INTO {} (ROWID, NAME, FIELDS, TEMPLATES, CLOZE) VALUES ({}, 'Basic', '{}', '{}', 0)",
+        NOTE_TYPES,
+        BASIC,
+        json!(["Front", "Back"]),
+        json!([["Card 1", "{{Front}}", "{{Back}}"]])
+    )).unwrap();
+    connection.execute(format!("INSERT OR IGNORE INTO {} (ROWID, NAME, FIELDS, TEMPLATES, CLOZE) VALUES ({}, 'Cloze', '{}', '{}', 1)",
+        NOTE_TYPES,
+        CLOZE,
+        json!(["Text", "Extra"]),
+        json!([["Cloze", "{{cloze:Text}}", "{{cloze:Text}}\n\n{{Extra}}"]])
+    )).unwrap();
+}
+
+/// Fills in a template with the values of a note's fields.
+///
+/// `{{Field}}` is replaced with the value of the field, `{{#Field}}...{{/Field}}` is only kept if the field isn't empty and
+/// `{{^Field}}...{{/Field}}` is only kept if it is. Values are inserted as they are, even if they look like markup.
+pub fn render(template: &str, fields: &[String], values: &[String]) -> String { render_card(template, fields, values, None) }
+
+/// Renders a template like `render`, also replacing `{{cloze:Field}}` with the field's clozes for the card of a cloze
+/// number if `cloze_card` is set to (number, reveal).
+fn render_card(template: &str, fields: &[String], values: &[String], cloze_card: Option<(i64, bool)>) -> String {
+    let mut template = template.to_string();
+    for (name, value) in fields.iter().zip(values) {
+        template = section(&template, &format!("{{{{#{}}}}}", name), &format!("{{{{/{}}}}}", name), !value.trim().is_empty());
+        template = section(&template, &format!("{{{{^{}}}}}", name), &format!("{{{{/{}}}}}", name), value.trim().is_empty());
+    }
+
+    // Fields are filled in a single pass over the template, so that a value is never searched for more fields.
+    let value = |name: &str| fields.iter().position(|field| field == name).and_then(|index| values.get(index));
+    let mut rendered = String::default();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end) else { break; };
+        let tag = &rest[start + 2..end];
+        let replacement = match (tag.strip_prefix("cloze:"), cloze_card) {
+            (Some(name), Some((number, reveal))) => value(name).map(|value| cloze(value, number, reveal)),
+            (Some(_), None) => None,
+            (None, _) => value(tag).cloned()
+        };
+        match replacement {
+            Some(replacement) => {
+                rendered.push_str(&rest[..start]);
+                rendered.push_str(&replacement);
+                rest = &rest[end + 2..];
+            }
+            // Anything else, like `{{FrontSide}}`, is kept, skipping only one brace so `{{{Field}}}` still works.
+            None => {
+                rendered.push_str(&rest[..start + 1]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+fn section(text: &str, open: &str, close: &str, keep: bool) -> String {
+    let mut text = text.to_string();
+    while let Some(start) = text.find(open) {
+        let end = match text[start..].find(close) {
+            Some(end) => start + end,
+            None => break
+        };
+        let inner = text[start + open.len()..end].to_string();
+        text.replace_range(start..end + close.len(), if keep { inner.as_str() } else { "" });
+    }
+
+    text
+}
+
+/// Renders the cards a note should have as (template, obverse, reverse).
+///
+/// The first template always makes a card, so that a note is never left without one, while the others only make one if
+/// their obverse uses a field that isn't empty. `{{FrontSide}}` on the reverse is replaced with the rendered obverse.
+///
+/// Cloze note types instead make a card for each cloze number used in their `{{cloze:Field}}` fields, whose template is the
+/// cloze number minus one.
+pub fn render_cards(connection: &Connection, note_type: i64, values: &[String]) -> Vec<(i64, String, String)> {
+    let (_, _, fields, templates, cloze) = get_note_type(connection, note_type);
+    if cloze { return render_clozes(&fields, &templates, values); }
+    let empty: Vec<String> = fields.iter().map(|_| String::default()).collect();
+    let mut cards: Vec<(i64, String, String)> = Vec::new();
+    for (template, (_, obverse, reverse)) in templates.iter().enumerate() {
+        let front = render(obverse, &fields, values);
+        if template == 0 || front != render(obverse, &fields, &empty) {
+            cards.push((template as i64, front.clone(), render(reverse, &fields, values).replace("{{FrontSide}}", &front)));
+        }
+    }
+
+    cards
+}
+
+fn render_clozes(fields: &[String], templates: &[(String, String, String)], values: &[String]) -> Vec<(i64, String, String)> {
+    let (_, obverse, reverse) = match templates.first() {
+        Some(template) => template,
+        None => return Vec::new()
+    };
+    let mut numbers: Vec<i64> = Vec::new();
+    for (name, value) in fields.iter().zip(values) {
+        if obverse.contains(&format!("{{{{cloze:{}}}}}", name)) { numbers.extend(cloze_numbers(value)); }
+    }
+    numbers.sort();
+    numbers.dedup();
+    if numbers.is_empty() { numbers.push(1); }
+
+    let mut cards: Vec<(i64, String, String)> = Vec::new();
+    for number in numbers {
+        let front = render_card(obverse, fields, values, Some((number, false)));
+        cards.push((number - 1, front.clone(), render_card(reverse, fields, values, Some((number, true))).replace("{{FrontSide}}", &front)));
+    }
+
+    cards
+}
+
+/// Finds every cloze in a text as (start, end, number, answer, hint). Braces inside a cloze, such as ruby, are matched so
+/// that `{{c1::{話|はなし}}}` ends after the ruby. Clozes numbered below 1, or too long to read, are left as text.
+fn clozes(text: &str) -> Vec<(usize, usize, i64, String, Option<String>)> {
+    let mut clozes: Vec<(usize, usize, i64, String, Option<String>)> = Vec::new();
+    let mut position = 0;
+    while let Some(start) = text[position..].find("{{c").map(|start| position + start) {
+        position = start + 3;
+        let digits: String = text[position..].chars().take_while(|char| char.is_ascii_digit()).collect();
+        if !text[position + digits.len()..].starts_with("::") { continue; }
+        let number: i64 = match digits.parse() {
+            Ok(number) if number >= 1 => number,
+            _ => continue
+        };
+        let content = position + digits.len() + 2;
+        let mut depth = 0;
+        let mut end = None;
+        for (index, char) in text[content..].char_indices() {
+            match char {
+                '{' => depth += 1,
+                '}' if depth > 0 => depth -= 1,
+                '}' if text[content + index..].starts_with("}}") => {
+                    end = Some(content + index);
+                    break;
+                }
+                _ => ()
+            }
+        }
+        let end = match end {
+            Some(end) => end,
+            None => break
+        };
+        let (answer, hint) = match text[content..end].split_once("::") {
+            Some((answer, hint)) => (answer.to_string(), Some(hint.to_string())),
+            None => (text[content..end].to_string(), None)
+        };
+        clozes.push((start, end + 2, number, answer, hint));
+        position = end + 2;
+    }
+
+    clozes
+}
+
+pub fn cloze_numbers(text: &str) -> Vec<i64> { clozes(text).iter().map(|cloze| cloze.2).collect() }
+
+/// Renders the clozes in a text for the card of `number`. Its clozes are shown as a blank (or their hint) on the obverse
+/// and in bold on the reverse, and every other cloze is shown as its answer.
+pub fn cloze(text: &str, number: i64, reveal: bool) -> String {
+    let mut rendered = String::default();
+    let mut position = 0;
+    for (start, end, cloze, answer, hint) in clozes(text) {
+        rendered.push_str(&text[position..start]);
+        if cloze != number { rendered.push_str(&answer); }
+        else if reveal { rendered.push_str(&format!("**{}**", answer)); }
+        else { rendered.push_str(&format!("**（{}）**", hint.unwrap_or("　".to_string()))); }
+        position = end;
+    }
+    rendered.push_str(&text[position..]);
+
+    rendered
+}
+
+pub fn get_note_types() -> Vec<NoteType> { list_note_types(&Connection::open(Cards::get_path()).unwrap()) }
+
+pub fn list_note_types(connection: &Connection) -> Vec<NoteType> {
+    let mut statement = connection.prepare(format!("SELECT ROWID FROM {}", NOTE_TYPES)).unwrap();
+    let mut note_types: Vec<NoteType> = Vec::new();
+    while let Ok(DBState::Row) = statement.next() { note_types.push(get_note_type(connection, statement.read::<i64, _>("rowid").unwrap())); }
+
+    note_types
+}
+
+pub fn get_note_type(connection: &Connection, id: i64) -> NoteType {
+    let mut statement = connection.prepare(format!("SELECT * FROM {} WHERE ROWID = {}", NOTE_TYPES, id)).unwrap();
+    statement.next().unwrap();
+    (
+        id,
+        statement.read::<String, _>("NAME").unwrap(),
+        serde_json::from_str(&statement.read::<String, _>("FIELDS").unwrap()).unwrap(),
+        serde_json::from_str(&statement.read::<String, _>("TEMPLATES").unwrap()).unwrap(),
+        statement.read::<i64, _>("CLOZE").unwrap() != 0
+    )
+}
+
+/// Cloze note types only use their first template. Every note type needs at least one template, so that its notes have
+/// cards.
+pub fn new_note_type(connection: &Connection, name: String, fields: Vec<String>, templates: Vec<Template>, cloze: bool) -> Result<i64, String> {
+    if templates.is_empty() { return Err(format!("The note type {} has no templates", name)); }
+    let mut statement = connection.prepare(format!("INSERT INTO {} (NAME, FIELDS, TEMPLATES, CLOZE) VALUES ('{}', '{}', '{}', {}) RETURNING ROWID",
+        NOTE_TYPES,
+        name.replace('\'', "''"),
+        json!(fields).to_string().replace('\'', "''"),
+        json!(templates).to_string().replace('\'', "''"),
+        cloze as i64
+    )).unwrap();
+    statement.next().unwrap();
+    Ok(statement.read::<i64, _>("rowid").unwrap())
+}
+
+/// Finds a note type with the same fields, templates and kind, so that imports don't duplicate note types, or creates one.
+pub fn find_note_type(connection: &Connection, name: String, fields: Vec<String>, templates: Vec<Template>, cloze: bool) -> Result<i64, String> {
+    match list_note_types(connection).into_iter().find(|note_type| note_type.2 == fields && note_type.3 == templates && note_type.4 == cloze) {
+        Some(note_type) => Ok(note_type.0),
+        None => new_note_type(connection, name, fields, templates, cloze)
+    }
+}
+
+/// Fields are matched to the values of existing notes by position, so adding a field should append it. Like `new_note_type`,
+/// the note type needs at least one template.
+pub fn edit_note_type(connection: &Connection, id: i64, name: String, fields: Vec<String>, templates: Vec<Template>) -> Result<(), String> {
+    if templates.is_empty() { return Err(format!("The note type {} has no templates", name)); }
+    connection.execute(format!("UPDATE {} SET NAME = '{}', FIELDS = '{}', TEMPLATES = '{}' WHERE ROWID = {}",
+        NOTE_TYPES,
+        name.replace('\'', "''"),
+        json!(fields).to_string().replace('\'', "''"),
+        json!(templates).to_string().replace('\'', "''"),
+        id
+    )).unwrap();
+
+    let mut statement = connection.prepare(format!("SELECT ROWID, FIELDS FROM {} WHERE NOTE_TYPE = {}", NOTES, id)).unwrap();
+    let mut notes: Vec<(i64, Vec<String>)> = Vec::new();
+    while let Ok(DBState::Row) = statement.next() {
+        notes.push((statement.read::<i64, _>("rowid").unwrap(), serde_json::from_str(&statement.read::<String, _>("FIELDS").unwrap()).unwrap()));
+    }
+    for (note, mut values) in notes {
+        values.resize(fields.len(), String::default());
+        set_fields(connection, note, &values);
+        sync_note(connection, note, None);
+    }
+
+    Ok(())
+}
+
+/// Note types can only be deleted once no notes use them, and the built in ones can't be deleted at all.
+pub fn delete_note_type(id: i64) -> Result<(), ()> {
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let mut statement = connection.prepare(format!("SELECT COUNT(*) AS COUNT FROM {} WHERE NOTE_TYPE = {}", NOTES, id)).unwrap();
+    statement.next().unwrap();
+    if id == BASIC || id == CLOZE || statement.read::<i64, _>("COUNT").unwrap() != 0 { return Err(()); }
+    connection.execute(format!("DELETE FROM {} WHERE ROWID = {}", NOTE_TYPES, id)).unwrap();
+    Ok(())
+}
+
+pub fn get_note(id: i64) -> (i64, Vec<String>) { read_note(&Connection::open(Cards::get_path()).unwrap(), id) }
+
+pub fn read_note(connection: &Connection, id: i64) -> (i64, Vec<String>) {
+    let mut statement = connection.prepare(format!("SELECT * FROM {} WHERE ROWID = {}", NOTES, id)).unwrap();
+    statement.next().unwrap();
+    (statement.read::<i64, _>("NOTE_TYPE").unwrap(), serde_json::from_str(&statement.read::<String, _>("FIELDS").unwrap()).unwrap())
+}
+
+pub fn insert_note(connection: &Connection, note_type: i64, values: &[String]) -> i64 {
+    let mut statement = connection.prepare(format!("INSERT INTO {} (NOTE_TYPE, FIELDS) VALUES ({}, '{}') RETURNING ROWID",
+        NOTES,
+        note_type,
+        json!(values).to_string().replace('\'', "''")
+    )).unwrap();
+    statement.next().unwrap();
+    statement.read::<i64, _>("rowid").unwrap()
+}
+
+pub fn set_fields(connection: &Connection, note: i64, values: &[String]) {
+    connection.execute(format!("UPDATE {} SET FIELDS = '{}' WHERE ROWID = {}", NOTES, json!(values).to_string().replace('\'', "''"), note)).unwrap();
+}
+
+/// Every card made from a note as (deck, id, template, reversed), ordered by template with reversed cards last.
+pub fn note_cards(connection: &Connection, note: i64) -> Vec<(String, i64, i64, bool)> {
+    let mut cards: Vec<(String, i64, i64, bool)> = Vec::new();
+    for deck in Cards::list_decks(connection) {
+        let mut statement = connection.prepare(format!("SELECT ROWID, TEMPLATE, REVERSED FROM \"{}\" WHERE NOTE = {}", deck.replace(r#"""#, r#""""#), note)).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            cards.push((deck.clone(), statement.read::<i64, _>("rowid").unwrap(), statement.read::<i64, _>("TEMPLATE").unwrap(), statement.read::<i64, _>("REVERSED").unwrap() != 0));
+        }
+    }
+    cards.sort_by_key(|card| (card.2, card.3));
+
+    cards
+}
+
+/// Re-renders the cards of a note and adds any that its templates now make.
+///
+/// New cards are added to `deck`, or to the deck of the note's first card if it is `None`, in which case a note without any
+/// cards is left alone. Cards in decks with reverse cards enabled get a reversed sibling in the same deck, unless they are
+/// cloze cards. Cards that a template no longer makes are kept so their progress isn't lost, except the cards of clozes that
+/// were removed from the note, which are deleted along with their reviews.
+pub fn sync_note(connection: &Connection, note: i64, deck: Option<&str>) -> Vec<(String, i64, i64, bool)> {
+    let (note_type, values) = {
+        let mut statement = connection.prepare(format!("SELECT * FROM {} WHERE ROWID = {}", NOTES, note)).unwrap();
+        statement.next().unwrap();
+        (statement.read::<i64, _>("NOTE_TYPE").unwrap(), serde_json::from_str::<Vec<String>>(&statement.read::<String, _>("FIELDS").unwrap()).unwrap())
+    };
+    let existing = note_cards(connection, note);
+    let deck = match (deck, existing.first()) {
+        (Some(deck), _) => deck.to_string(),
+        (None, Some(card)) => card.0.clone(),
+        (None, None) => return existing
+    };
+
+    let cloze = get_note_type(connection, note_type).4;
+    let rendered = render_cards(connection, note_type, &values);
+    let templates: Vec<i64> = rendered.iter().map(|card| card.0).collect();
+    for (template, obverse, reverse) in rendered {
+        let card_deck = match existing.iter().find(|card| card.2 == template && !card.3) {
+            Some((card_deck, id, _, _)) => {
+                update_content(connection, card_deck, *id, &obverse, &reverse);
+                card_deck.clone()
+            }
+            None => {
+                Cards::insert_card(connection, &deck, obverse.clone(), reverse.clone(), note, template, false);
+                deck.clone()
+            }
+        };
+        match existing.iter().find(|card| card.2 == template && card.3) {
+            Some((sibling_deck, id, _, _)) => update_content(connection, sibling_deck, *id, &reverse, &obverse),
+            None if !cloze && Cards::get_deck_options_with(connection, &card_deck)["reverse"] == true => { Cards::insert_card(connection, &card_deck, reverse, obverse, note, template, true); }
+            None => ()
+        }
+    }
+
+    let removed: Vec<&(String, i64, i64, bool)> = existing.iter().filter(|card| cloze && !templates.contains(&card.2)).collect();
+    for (card_deck, id, _, _) in &removed {
+        connection.execute(format!("DELETE FROM \"{}\" WHERE ROWID = {}", card_deck.replace(r#"""#, r#""""#), id)).unwrap();
+        Cards::touch(connection, card_deck);
+    }
+    if !removed.is_empty() { reviews::clean(connection); }
+
+    note_cards(connection, note)
+}
+
+fn update_content(connection: &Connection, deck: &str, id: i64, obverse: &str, reverse: &str) {
+    connection.execute(format!("UPDATE \"{}\" SET OBVERSE = '{}', REVERSE = '{}' WHERE ROWID = {}",
+        deck.replace(r#"""#, r#""""#),
+        obverse.replace('\'', "''"),
+        reverse.replace('\'', "''"),
+        id
+    )).unwrap();
+    Cards::touch(connection, deck);
+}
+
+/// Removes notes that no longer have any cards.
+pub fn clean(connection: &Connection) {
+    let decks: Vec<String> = Cards::list_decks(connection).iter().map(|deck| format!("SELECT NOTE FROM \"{}\"", deck.replace(r#"""#, r#""""#))).collect();
+    if decks.is_empty() { connection.execute(format!("DELETE FROM {}", NOTES)).unwrap(); }
+    else { connection.execute(format!("DELETE FROM {} WHERE ROWID NOT IN ({})", NOTES, decks.join(" UNION "))).unwrap(); }
+}
+
+/// Returns the note of a card as (note, note type).
+pub fn card_note(connection: &Connection, deck: &str, id: i64) -> (i64, i64) {
+    let mut statement = connection.prepare(format!("SELECT NOTE FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+    statement.next().unwrap();
+    let note = statement.read::<i64, _>("NOTE").unwrap();
+    let mut statement = connection.prepare(format!("SELECT NOTE_TYPE FROM {} WHERE ROWID = {}", NOTES, note)).unwrap();
+    statement.next().unwrap();
+    (note, statement.read::<i64, _>("NOTE_TYPE").unwrap())
+}
@@ -0,0 +1,176 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlite::Connection;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use zip::{ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+use crate::cards::ImportReport;
+use crate::{deck_file, media};
+
+/// The version of the package format that `export` writes. Packages from later versions are refused.
+pub const VERSION: i64 = 1;
+
+/// What a package says about the deck in it, which is written to `manifest.json` with `format` set to `"srs-package"` and
+/// `format_version` set to `VERSION`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Manifest {
+    /// The name the deck is imported as. The name of the exported deck if empty.
+    pub title: String,
+    pub description: String,
+    pub author: String,
+    pub license: String,
+    /// The version of the deck, for telling updates of a shared deck apart.
+    pub version: String,
+    /// The language of the deck, as a tag like `ja` or `pt-BR`.
+    pub language: String,
+    /// Filled in by `export`.
+    pub cards: usize
+}
+
+/// Exports a deck as a package for sharing: a zip of `manifest.json`, the deck in the srs deck format as `deck.srs`, and
+/// the media its cards use in `media/`. Fields of the manifest that are left empty are filled in from the deck's metadata.
+/// Without `progress`, cards are exported as new.
+pub fn export(deck: &str, path: &str, mut manifest: Manifest, progress: bool) -> Result<(), String> {
+    let file = deck_file::deck_json(deck, progress)?;
+    let cards = file["cards"].as_array().unwrap();
+    if manifest.title.trim().is_empty() { manifest.title = deck.to_string(); }
+    // Anything left empty is taken from the deck's metadata.
+    let metadata = &file["metadata"];
+    for (field, key) in [
+        (&mut manifest.description, "description"),
+        (&mut manifest.author, "author"),
+        (&mut manifest.license, "license"),
+        (&mut manifest.version, "version"),
+        (&mut manifest.language, "language")
+    ] {
+        if field.trim().is_empty() { *field = metadata[key].as_str().unwrap_or_default().to_string(); }
+    }
+    manifest.cards = cards.len();
+    let mut manifest = serde_json::to_value(manifest).map_err(|error| error.to_string())?;
+    manifest["format"] = json!("srs-package");
+    manifest["format_version"] = json!(VERSION);
+
+    let mut names: Vec<String> = Vec::new();
+    for card in cards {
+        for side in ["obverse", "reverse"] {
+            for name in media::references(card[side].as_str().unwrap_or_default()) { if !names.contains(&name) { names.push(name); } }
+        }
+    }
+
+    let mut zip = ZipWriter::new(fs::File::create(path).map_err(|error| error.to_string())?);
+    zip.start_file("manifest.json", FileOptions::default()).map_err(|error| error.to_string())?;
+    zip.write_all(serde_json::to_string_pretty(&manifest).unwrap().as_bytes()).map_err(|error| error.to_string())?;
+    zip.start_file("deck.srs", FileOptions::default()).map_err(|error| error.to_string())?;
+    zip.write_all(file.to_string().as_bytes()).map_err(|error| error.to_string())?;
+    for name in names.iter().filter(|name| media::media_dir().join(name).is_file()) {
+        zip.start_file(format!("media/{}", name), FileOptions::default()).map_err(|error| error.to_string())?;
+        zip.write_all(&fs::read(media::media_dir().join(name)).map_err(|error| error.to_string())?).map_err(|error| error.to_string())?;
+    }
+    zip.finish().map_err(|error| error.to_string())?;
+
+    Ok(())
+}
+
+/// Imports a package written by `export`, as a new deck named after its title or into the deck `into`, in the same way as
+/// the srs deck format, with the metadata of a new deck taken from the manifest. Every problem with the manifest is
+/// returned at once, and nothing is imported if there are any.
+///
+/// Media is stored under the name of its content, so references to it are renamed if they differ. If `store` isn't set,
+/// the media isn't copied into the media folder, for a dry run.
+pub fn import(connection: &Connection, path: &str, into: Option<&str>, remove: bool, store: bool) -> Result<ImportReport, Vec<String>> {
+    deck_file::import_value(connection, &read(path, store)?, into, remove).map_err(|error| vec![error])
+}
+
+/// Reads the deck in a package in the srs deck format, named after the package's title and with its metadata, after
+/// validating the manifest and storing the media as `import` describes.
+pub fn read(path: &str, store: bool) -> Result<Value, Vec<String>> {
+    let mut archive = ZipArchive::new(fs::File::open(path).map_err(|error| vec![error.to_string()])?).map_err(|error| vec![error.to_string()])?;
+    let manifest: Value = serde_json::from_str(&read_entry(&mut archive, "manifest.json")?).map_err(|error| vec![format!("The manifest isn't valid JSON: {}", error)])?;
+    let mut file: Value = serde_json::from_str(&read_entry(&mut archive, "deck.srs")?).map_err(|error| vec![format!("The deck isn't valid JSON: {}", error)])?;
+    let errors = validate(&manifest, &file);
+    if !errors.is_empty() { return Err(errors); }
+
+    let entries: Vec<String> = archive.file_names().filter(|name| name.starts_with("media/") && name.len() > "media/".len()).map(str::to_string).collect();
+    let mut names: HashMap<String, String> = HashMap::new();
+    for entry in entries {
+        let mut content: Vec<u8> = Vec::new();
+        archive.by_name(&entry).map_err(|error| vec![error.to_string()])?.read_to_end(&mut content).map_err(|error| vec![error.to_string()])?;
+        let Some(name) = Path::new(&entry).file_name().map(|name| name.to_string_lossy().to_string()) else { continue; };
+        let extension = Path::new(&name).extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_string();
+        let stored = if store { media::store(&content, &extension).map_err(|error| vec![error])? } else { media::name(&content, &extension) };
+        if stored != name { names.insert(name, stored); }
+    }
+    if !names.is_empty() {
+        for note in file["notes"].as_array_mut().into_iter().flatten() {
+            for field in note["fields"].as_array_mut().into_iter().flatten() {
+                if let Some(text) = field.as_str() { *field = json!(media::rename(text, &names)); }
+            }
+        }
+        for card in file["cards"].as_array_mut().into_iter().flatten() {
+            for side in ["obverse", "reverse"] {
+                if let Some(text) = card[side].as_str() { card[side] = json!(media::rename(text, &names)); }
+            }
+        }
+    }
+
+    file["name"] = manifest["title"].clone();
+    for key in ["description", "author", "license", "version", "language"] {
+        if manifest[key].as_str().is_some_and(|value| !value.is_empty()) { file["metadata"][key] = manifest[key].clone(); }
+    }
+
+    Ok(file)
+}
+
+/// The problems with a package's manifest, checked against the deck in it.
+fn validate(manifest: &Value, file: &Value) -> Vec<String> {
+    let mut errors: Vec<String> = Vec::new();
+    if manifest["format"] != "srs-package" { return vec!["This isn't an srs package".to_string()]; }
+    match manifest["format_version"].as_i64() {
+        Some(version) if version > VERSION => errors.push(format!("This package is from a newer version of srs (format version {})", version)),
+        Some(_) => (),
+        None => errors.push("The manifest has no format version".to_string())
+    }
+    match manifest["title"].as_str() {
+        Some(title) if !title.trim().is_empty() => (),
+        _ => errors.push("The manifest has no title".to_string())
+    }
+    for key in ["description", "author", "license", "version", "language"] {
+        if !manifest[key].is_null() && !manifest[key].is_string() { errors.push(format!("The {} in the manifest isn't a string", key)); }
+    }
+    if let Some(language) = manifest["language"].as_str().filter(|language| !language.is_empty()) {
+        if !Regex::new(r"^[A-Za-z]{2,3}(-[A-Za-z0-9]{2,8})*$").unwrap().is_match(language) {
+            errors.push(format!("{} isn't a language tag like ja or pt-BR", language));
+        }
+    }
+    match (manifest["cards"].as_u64(), file["cards"].as_array()) {
+        (_, None) => errors.push("The package has no cards".to_string()),
+        (Some(count), Some(cards)) if count as usize != cards.len() => errors.push(format!("The manifest lists {} cards, but the deck has {}", count, cards.len())),
+        (None, Some(_)) => errors.push("The manifest has no card count".to_string()),
+        _ => ()
+    }
+
+    errors
+}
+
+fn read_entry(archive: &mut ZipArchive<fs::File>, name: &str) -> Result<String, Vec<String>> {
+    let mut content = String::default();
+    archive.by_name(name).map_err(|_| vec![format!("The package has no {}", name)])?.read_to_string(&mut content).map_err(|error| vec![error.to_string()])?;
+
+    Ok(content)
+}
@@ -0,0 +1,194 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use chrono::{DateTime, NaiveDate, Utc};
+use csv::Writer;
+use fsrs::Card;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlite::{Connection, State as DBState};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::cards::Cards;
+
+/// Every review, keyed by the GUID of the card so that history follows cards between decks.
+pub const REVIEWS: &str = "srs_reviews";
+
+/// The columns of an exported review history. The first five are those of the FSRS optimizer's revlog format, with states
+/// numbered from 0 and times in milliseconds, so the file can be given to it as is.
+const HISTORY_COLUMNS: [&str; 14] = [
+    "card_id", "review_time", "review_rating", "review_state", "review_duration",
+    "guid", "deck", "reviewed", "elapsed_days", "scheduled_days", "stability_before", "difficulty_before", "stability_after", "difficulty_after"
+];
+
+/// Which reviews `export` writes, and how.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct HistoryOptions {
+    /// Only reviews of cards now in this deck.
+    pub deck: Option<String>,
+    /// The first day to include, as YYYY-MM-DD in UTC.
+    pub from: Option<String>,
+    /// The last day to include, as YYYY-MM-DD in UTC.
+    pub to: Option<String>,
+    /// `csv` or `jsonl`. Taken from the extension of the path if not set.
+    pub format: Option<String>
+}
+
+pub fn migrate(connection: &Connection) {
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (CARD TEXT, REVIEWED TEXT, RATING INTEGER, STATE INTEGER, ELAPSED_DAYS INTEGER, SCHEDULED_DAYS INTEGER, STABILITY REAL, DIFFICULTY REAL, DURATION INTEGER)", REVIEWS)).unwrap();
+    connection.execute(format!("CREATE INDEX IF NOT EXISTS {0}_card ON {0} (CARD)", REVIEWS)).unwrap();
+}
+
+/// Records the review that led to `card`, the state of the card after it. `duration` is how long the answer took in
+/// milliseconds, if known.
+pub fn log(connection: &Connection, guid: &str, card: &Card, duration: Option<i64>) {
+    let Some(log) = &card.log else { return; };
+    connection.execute(format!("INSERT INTO {} VALUES ('{}', '{}', {}, {}, {}, {}, {}, {}, {})",
+        REVIEWS,
+        guid.replace('\'', "''"),
+        log.reviewed_date.to_rfc3339(),
+        Cards::rating_number(log.rating),
+        Cards::state_number(log.state),
+        log.elapsed_days,
+        log.scheduled_days,
+        card.stability,
+        card.difficulty,
+        duration.map(|duration| duration.to_string()).unwrap_or("NULL".to_string())
+    )).unwrap();
+}
+
+/// The reviews of a card in order, with the same keys as the columns in lowercase.
+pub fn card_reviews(connection: &Connection, guid: &str) -> Vec<Value> {
+    let mut statement = connection.prepare(format!("SELECT * FROM {} WHERE CARD = '{}' ORDER BY REVIEWED", REVIEWS, guid.replace('\'', "''"))).unwrap();
+    let mut reviews: Vec<Value> = Vec::new();
+    while let Ok(DBState::Row) = statement.next() {
+        reviews.push(json!({
+            "reviewed": statement.read::<String, _>("REVIEWED").unwrap(),
+            "rating": statement.read::<i64, _>("RATING").unwrap(),
+            "state": statement.read::<i64, _>("STATE").unwrap(),
+            "elapsed_days": statement.read::<i64, _>("ELAPSED_DAYS").unwrap(),
+            "scheduled_days": statement.read::<i64, _>("SCHEDULED_DAYS").unwrap(),
+            "stability": statement.read::<f64, _>("STABILITY").unwrap(),
+            "difficulty": statement.read::<f64, _>("DIFFICULTY").unwrap(),
+            "duration": statement.read::<Option<i64>, _>("DURATION").unwrap()
+        }));
+    }
+
+    reviews
+}
+
+/// Adds reviews in the form `card_reviews` returns to a card.
+pub fn insert(connection: &Connection, guid: &str, reviews: &[Value]) {
+    for review in reviews {
+        connection.execute(format!("INSERT INTO {} VALUES ('{}', '{}', {}, {}, {}, {}, {}, {}, {})",
+            REVIEWS,
+            guid.replace('\'', "''"),
+            review["reviewed"].as_str().unwrap_or_default().replace('\'', "''"),
+            review["rating"].as_i64().unwrap_or_default(),
+            review["state"].as_i64().unwrap_or_default(),
+            review["elapsed_days"].as_i64().unwrap_or_default(),
+            review["scheduled_days"].as_i64().unwrap_or_default(),
+            review["stability"].as_f64().unwrap_or_default(),
+            review["difficulty"].as_f64().unwrap_or_default(),
+            review["duration"].as_i64().map(|duration| duration.to_string()).unwrap_or("NULL".to_string())
+        )).unwrap();
+    }
+}
+
+/// Removes the reviews of cards that no longer exist.
+pub fn clean(connection: &Connection) {
+    let decks: Vec<String> = Cards::list_decks(connection).iter().map(|deck| format!("SELECT GUID FROM \"{}\" WHERE GUID IS NOT NULL", deck.replace(r#"""#, r#""""#))).collect();
+    if decks.is_empty() { connection.execute(format!("DELETE FROM {}", REVIEWS)).unwrap(); }
+    else { connection.execute(format!("DELETE FROM {} WHERE CARD NOT IN ({})", REVIEWS, decks.join(" UNION "))).unwrap(); }
+}
+
+/// Parses a day given as YYYY-MM-DD.
+pub fn parse_day(date: &Option<String>) -> Result<Option<NaiveDate>, String> {
+    date.as_deref()
+        .map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| format!("{} isn't a date in the form YYYY-MM-DD", date)))
+        .transpose()
+}
+
+/// The deck of every card in `deck`, or in every deck if it's `None`, keyed by the cards' GUIDs.
+pub fn card_decks(connection: &Connection, deck: Option<String>) -> Result<HashMap<String, String>, String> {
+    let decks = match deck {
+        Some(deck) if Cards::list_decks(connection).contains(&deck) => vec![deck],
+        Some(deck) => return Err(format!("There is no deck named {}", deck)),
+        None => Cards::list_decks(connection)
+    };
+    let mut card_decks: HashMap<String, String> = HashMap::new();
+    for deck in decks {
+        let mut statement = connection.prepare(format!("SELECT GUID FROM \"{}\" WHERE GUID IS NOT NULL", deck.replace(r#"""#, r#""""#))).unwrap();
+        while let Ok(DBState::Row) = statement.next() { card_decks.insert(statement.read::<String, _>("GUID").unwrap(), deck.clone()); }
+    }
+
+    Ok(card_decks)
+}
+
+/// Exports the review history of every card, or of the cards in one deck, in order, as CSV or JSON Lines with the keys in
+/// `HISTORY_COLUMNS`. Cards are numbered in the order they were first reviewed, and the stability and difficulty before a
+/// review are those after the card's previous one, which are empty for its first.
+pub fn export(path: &str, options: HistoryOptions) -> Result<(), String> {
+    let (from, to) = (parse_day(&options.from)?, parse_day(&options.to)?);
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let card_decks = card_decks(&connection, options.deck)?;
+
+    let mut card_ids: HashMap<String, usize> = HashMap::new();
+    let mut previous: HashMap<String, (f64, f64)> = HashMap::new();
+    let mut rows: Vec<Value> = Vec::new();
+    let mut statement = connection.prepare(format!("SELECT * FROM {} ORDER BY REVIEWED", REVIEWS)).unwrap();
+    while let Ok(DBState::Row) = statement.next() {
+        let guid = statement.read::<String, _>("CARD").unwrap();
+        let Some(deck) = card_decks.get(&guid) else { continue; };
+        let reviewed = statement.read::<String, _>("REVIEWED").unwrap();
+        let time = DateTime::parse_from_rfc3339(&reviewed).map_err(|error| error.to_string())?.with_timezone(&Utc);
+        let after = (statement.read::<f64, _>("STABILITY").unwrap(), statement.read::<f64, _>("DIFFICULTY").unwrap());
+        let before = previous.insert(guid.clone(), after);
+        if from.is_some_and(|from| time.date_naive() < from) || to.is_some_and(|to| time.date_naive() > to) { continue; }
+
+        let next_id = card_ids.len() + 1;
+        rows.push(json!({
+            "card_id": *card_ids.entry(guid.clone()).or_insert(next_id),
+            "review_time": time.timestamp_millis(),
+            "review_rating": statement.read::<i64, _>("RATING").unwrap(),
+            "review_state": statement.read::<i64, _>("STATE").unwrap() - 1,
+            "review_duration": statement.read::<Option<i64>, _>("DURATION").unwrap().unwrap_or_default(),
+            "guid": guid,
+            "deck": deck,
+            "reviewed": reviewed,
+            "elapsed_days": statement.read::<i64, _>("ELAPSED_DAYS").unwrap(),
+            "scheduled_days": statement.read::<i64, _>("SCHEDULED_DAYS").unwrap(),
+            "stability_before": before.map(|before| before.0),
+            "difficulty_before": before.map(|before| before.1),
+            "stability_after": after.0,
+            "difficulty_after": after.1
+        }));
+    }
+
+    let format = options.format.or_else(|| Path::new(path).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase));
+    match format.as_deref() {
+        Some("jsonl") | Some("ndjson") => fs::write(path, rows.iter().map(|row| format!("{}\n", row)).collect::<String>()).map_err(|error| error.to_string()),
+        _ => {
+            let mut writer = Writer::from_path(path).map_err(|error| error.to_string())?;
+            writer.write_record(HISTORY_COLUMNS).map_err(|error| error.to_string())?;
+            for row in rows {
+                writer.write_record(HISTORY_COLUMNS.map(|column| match &row[column] {
+                    Value::String(value) => value.clone(),
+                    Value::Null => String::default(),
+                    value => value.to_string()
+                })).map_err(|error| error.to_string())?;
+            }
+            writer.flush().map_err(|error| error.to_string())
+        }
+    }
+}
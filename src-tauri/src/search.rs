@@ -0,0 +1,123 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use std::sync::OnceLock;
+use crate::cards::{Cards, ListedCard};
+
+/// Splits the value of a `prop:` term into (property, comparison, number).
+static PROPERTY: OnceLock<Regex> = OnceLock::new();
+
+/// Returns every card matching a query, in the same form as `get_deck`.
+///
+/// A query is a list of terms separated by whitespace, all of which a card has to match. Terms can be quoted to include
+/// whitespace, with `\"` for a quote and `\\` for a backslash inside the quotes, and are negated by a leading `-`. Plain
+/// terms match either side of a card, ignoring formatting and case, while the following match something else:
+///
+/// | Term                                                  | Matches                                      |
+/// |-------------------------------------------------------|----------------------------------------------|
+/// | `deck:Name`                                           | Cards in the deck                            |
+/// | `tag:name`                                            | Cards with the tag                           |
+/// | `note:id`                                             | Cards made from the note                     |
+/// | `front:text`, `back:text`                             | Cards with the text on that side             |
+/// | `is:new`, `is:learning`, `is:review`, `is:relearning` | Cards in that state                          |
+/// | `is:suspended`, `is:buried`, `is:reversed`            | Cards that are suspended, buried or reversed |
+/// | `is:leech`                                            | Cards past their deck's leech threshold      |
+/// | `prop:r<0.8`                                          | Cards whose property compares to the number  |
+///
+/// `prop:` terms compare retrievability (`r`), stability (`s`), difficulty (`d`), `reps` or `lapses` with `<`, `<=`, `>`,
+/// `>=`, `=` or `!=`. Only cards in review have a retrievability, so no others match a comparison of it.
+pub fn search(query: &str) -> Vec<ListedCard> {
+    let terms = parse(query);
+    let connection = sqlite::Connection::open(Cards::get_path()).unwrap();
+    let mut cards: Vec<ListedCard> = Vec::new();
+    for deck in Cards::get_decks() {
+        // Skip loading decks that a `deck:` term already rules out.
+        if terms.iter().any(|(negated, key, value)| !negated && key == "deck" && !deck.eq_ignore_ascii_case(value)) { continue; }
+        let threshold = Cards::leech_threshold(&connection, &deck);
+        cards.extend(Cards::get_deck(deck).into_iter().filter(|card| terms.iter().all(|(negated, key, value)| matches(card, key, value, threshold) != *negated)));
+    }
+
+    cards
+}
+
+/// Splits a query into (negated, key, value) terms, where the key is empty for plain terms.
+fn parse(query: &str) -> Vec<(bool, String, String)> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut token = String::default();
+    let mut quoted = false;
+    let mut chars = query.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' if quoted && matches!(chars.peek(), Some('"') | Some('\\')) => token.push(chars.next().unwrap()),
+            '"' => quoted = !quoted,
+            _ if char.is_whitespace() && !quoted => if !token.is_empty() { tokens.push(std::mem::take(&mut token)); },
+            _ => token.push(char)
+        }
+    }
+    if !token.is_empty() { tokens.push(token); }
+
+    tokens.into_iter().map(|token| {
+        let (negated, token) = match token.strip_prefix('-') {
+            Some(token) if !token.is_empty() => (true, token.to_string()),
+            _ => (false, token)
+        };
+        match token.split_once(':') {
+            Some((key, value)) if ["deck", "tag", "note", "front", "back", "is", "prop"].contains(&key.to_lowercase().as_str()) => (negated, key.to_lowercase(), value.to_string()),
+            _ => (negated, String::default(), token)
+        }
+    }).collect()
+}
+
+fn matches(card: &ListedCard, key: &str, value: &str, threshold: i64) -> bool {
+    let (deck, _, obverse, reverse, state, properties) = card;
+    match key {
+        "deck" => deck.eq_ignore_ascii_case(value),
+        "tag" => properties["tags"].as_array().unwrap().iter().any(|tag| tag.as_str().unwrap().eq_ignore_ascii_case(value)),
+        "note" => properties["note"].as_i64().map(|note| note.to_string()) == Some(value.to_string()),
+        "front" => Cards::normalize(obverse).contains(&Cards::normalize(value)),
+        "back" => Cards::normalize(reverse).contains(&Cards::normalize(value)),
+        "is" => match value.to_lowercase().as_str() {
+            "new" => state["state"] == 1,
+            "learning" => state["state"] == 2,
+            "review" => state["state"] == 3,
+            "relearning" => state["state"] == 4,
+            "suspended" => properties["suspended"] == true,
+            "buried" => properties["buried_until"].as_str().map(|buried_until| DateTime::<Utc>::from(DateTime::parse_from_rfc3339(buried_until).unwrap()) > Utc::now()).unwrap_or(false),
+            "reversed" => properties["reversed"] == true,
+            "leech" => threshold > 0 && state["lapses"].as_i64().unwrap() >= threshold,
+            _ => false
+        },
+        "prop" => {
+            let Some(captures) = PROPERTY.get_or_init(|| Regex::new(r"^(\w+)(<=|>=|!=|<|>|=)(.+)$").unwrap()).captures(value) else { return false; };
+            let Ok(number) = captures[3].parse::<f64>() else { return false; };
+            let property = match captures[1].to_lowercase().as_str() {
+                "r" => properties["retrievability"].as_f64(),
+                "s" => state["stability"].as_f64(),
+                "d" => state["difficulty"].as_f64(),
+                "reps" => state["reps"].as_f64(),
+                "lapses" => state["lapses"].as_f64(),
+                _ => None
+            };
+            let Some(property) = property else { return false; };
+            match &captures[2] {
+                "<" => property < number,
+                "<=" => property <= number,
+                ">" => property > number,
+                ">=" => property >= number,
+                "=" => property == number,
+                _ => property != number
+            }
+        },
+        _ => Cards::normalize(obverse).contains(&Cards::normalize(value)) || Cards::normalize(reverse).contains(&Cards::normalize(value))
+    }
+}
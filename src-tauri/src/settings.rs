@@ -9,8 +9,6 @@ This Source Code Form is "Incompatible With Secondary Licenses", as
 defined by the Mozilla Public License, v. 2.0.
 */
 
-use dirs;
-use serde_json;
 use serde_json::{json, Value};
 use std::fs;
 use std::io::{Read, Write};
@@ -23,19 +21,30 @@ impl Settings {
     pub fn default() -> Settings {
         let mut content = String::default();
         fs::File::open(Self::get_path()).unwrap().read_to_string(&mut content).unwrap();
-        let val: Value = serde_json::from_str(&content).unwrap();
+        let mut val: Value = Self::defaults();
+        for (key, value) in serde_json::from_str::<Value>(&content).unwrap().as_object().unwrap() { val[key] = value.clone(); }
         Settings { settings: Box::new(Mutex::from(val.clone())) }
     }
 
+    /// Values used for any setting missing from the config file, such as ones added after it was created.
+    fn defaults() -> Value {
+        json!({
+            "new_cards": 20,
+            "leech_threshold": 8,
+            "leech_actions": ["tag"],
+            "duplicate_scope": "deck",
+            "day_boundary": 4,
+            "review_order": "due"
+        })
+    }
+
     fn get_path() -> String {
         let json_path = dirs::data_dir().unwrap().to_str().unwrap().to_string() + "/srs/config.json";
         let json_file = Path::new(&json_path);
         if !json_file.exists() {
             let json_dir = Path::new(&json_path).parent().unwrap();
             fs::create_dir_all(json_dir).unwrap();
-            fs::File::create(json_file).unwrap().write(json!({
-                "new_cards": 20
-            }).to_string().as_bytes()).unwrap();
+            fs::File::create(json_file).unwrap().write_all(Self::defaults().to_string().as_bytes()).unwrap();
         }
 
         json_path
@@ -46,7 +55,10 @@ impl Settings {
     pub fn get_from_file(key: &str) -> Value {
         let mut content = String::default();
         fs::File::open(Self::get_path()).unwrap().read_to_string(&mut content).unwrap();
-        serde_json::from_str::<Value>(&content).unwrap()[key].clone()
+        match serde_json::from_str::<Value>(&content).unwrap()[key].clone() {
+            Value::Null => Self::defaults()[key].clone(),
+            value => value
+        }
     }
 
     pub fn set(&self, value: Value) { *(self.settings.lock().unwrap()) = value; }
@@ -56,7 +68,7 @@ impl Settings {
             .write(true)
             .truncate(true)
             .open(Self::get_path()).unwrap()
-            .write_all(&*self.settings.lock().unwrap().to_string().as_bytes())
+            .write_all(self.settings.lock().unwrap().to_string().as_bytes())
             .unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,330 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlite::{Connection, State as DBState};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::cards::Cards;
+use crate::reviews;
+
+/// The interval in days at which a card counts as mature rather than young.
+const MATURE: i64 = 21;
+
+/// Which reviews `statistics` counts.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct StatsOptions {
+    /// Only cards now in this deck.
+    pub deck: Option<String>,
+    /// The first day to include, as YYYY-MM-DD.
+    pub from: Option<String>,
+    /// The last day to include, as YYYY-MM-DD.
+    pub to: Option<String>
+}
+
+/// How many days `activity` covers, ending with today.
+const ACTIVITY_DAYS: i64 = 365;
+
+/// Which cards `forecast` counts, and how far ahead.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ForecastOptions {
+    /// Only cards in this deck.
+    pub deck: Option<String>,
+    /// How many days to forecast, starting with today.
+    pub days: i64,
+    /// Whether to count the new cards that the daily limit would introduce.
+    pub new: bool
+}
+
+impl Default for ForecastOptions {
+    fn default() -> ForecastOptions { ForecastOptions { deck: None, days: 30, new: false } }
+}
+
+/// The cards due on one day, for `forecast`.
+#[derive(Serialize, Default, Clone)]
+struct Due {
+    date: String,
+    review: i64,
+    /// Cards in learning or relearning.
+    learning: i64,
+    new: i64,
+    total: i64
+}
+
+/// What happened on one day, for `statistics`.
+#[derive(Serialize, Default)]
+struct Day {
+    date: String,
+    again: i64,
+    hard: i64,
+    good: i64,
+    easy: i64,
+    /// New cards studied for the first time.
+    learned: i64,
+    /// Time spent answering, in milliseconds.
+    time: i64
+}
+
+/// Every card whose lapses have reached its deck's leech threshold, most lapsed first.
+pub fn leeches() -> Vec<Value> {
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let mut leeches: Vec<Value> = Vec::new();
+    for deck in Cards::list_decks(&connection) {
+        let threshold = Cards::leech_threshold(&connection, &deck);
+        if threshold <= 0 { continue; }
+        for card in Cards::get_deck(deck) {
+            if card.4["lapses"].as_i64().unwrap() >= threshold {
+                leeches.push(json!({
+                    "deck": card.0,
+                    "id": card.1,
+                    "obverse": card.2,
+                    "reverse": card.3,
+                    "lapses": card.4["lapses"],
+                    "suspended": card.5["suspended"],
+                    "tags": card.5["tags"]
+                }));
+            }
+        }
+    }
+    leeches.sort_by_key(|leech| -leech["lapses"].as_i64().unwrap());
+
+    leeches
+}
+
+/// Statistics on the reviews of every card, or of the cards now in one deck, between two days, which start at the hour set
+/// by `day_boundary`.
+///
+/// The result has the reviews of each day with any in `days`, as the number of each rating, the number of new cards
+/// `learned` and the `time` spent in milliseconds, along with the totals of those as `reviews`, `learned` and `time`.
+/// `retention` is the true retention of `young` and `mature` cards: of the reviews of cards in review, how many were
+/// `passed` rather than forgotten, out of the `total`, and the `rate`, which is null without any reviews. `counts` is the
+/// number of cards in each state now, with suspended cards only counted as `suspended`. `retrievability` is the average
+/// and distribution of the current retrievability of every card in review, as `summarize` returns them, along with the
+/// same for each deck in `decks`.
+pub fn statistics(options: StatsOptions) -> Result<Value, String> {
+    let (from, to) = (reviews::parse_day(&options.from)?, reviews::parse_day(&options.to)?);
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let card_decks = reviews::card_decks(&connection, options.deck.clone())?;
+
+    let boundary = Cards::day_boundary();
+    let mut days: BTreeMap<NaiveDate, Day> = BTreeMap::new();
+    // The passed and total reviews of young and of mature cards.
+    let mut retention = [(0, 0), (0, 0)];
+    // The interval each card was on before its review, which is the one its previous review set.
+    let mut intervals: HashMap<String, i64> = HashMap::new();
+    let mut statement = connection.prepare(format!("SELECT * FROM {} ORDER BY REVIEWED", reviews::REVIEWS)).unwrap();
+    while let Ok(DBState::Row) = statement.next() {
+        let guid = statement.read::<String, _>("CARD").unwrap();
+        if !card_decks.contains_key(&guid) { continue; }
+        let interval = intervals.insert(guid, statement.read::<i64, _>("SCHEDULED_DAYS").unwrap()).unwrap_or_default();
+        let date = Cards::day(DateTime::parse_from_rfc3339(&statement.read::<String, _>("REVIEWED").unwrap()).map_err(|error| error.to_string())?.with_timezone(&Utc), boundary);
+        if from.is_some_and(|from| date < from) || to.is_some_and(|to| date > to) { continue; }
+
+        let (rating, state) = (statement.read::<i64, _>("RATING").unwrap(), statement.read::<i64, _>("STATE").unwrap());
+        let day = days.entry(date).or_insert_with(|| Day { date: date.to_string(), ..Day::default() });
+        match rating {
+            1 => day.again += 1,
+            2 => day.hard += 1,
+            3 => day.good += 1,
+            _ => day.easy += 1
+        }
+        if state == 1 { day.learned += 1; }
+        day.time += statement.read::<Option<i64>, _>("DURATION").unwrap().unwrap_or_default();
+        // Reviews of cards in review, and not in learning or relearning, are the ones that test long-term memory.
+        if state == 3 {
+            let retention = &mut retention[(interval >= MATURE) as usize];
+            if rating > 1 { retention.0 += 1; }
+            retention.1 += 1;
+        }
+    }
+
+    let decks = match options.deck {
+        Some(deck) => vec![deck],
+        None => Cards::list_decks(&connection)
+    };
+    let mut counts: BTreeMap<&str, i64> = ["new", "learning", "review", "relearning", "suspended"].into_iter().map(|state| (state, 0)).collect();
+    let mut retrievabilities: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for deck in decks {
+        retrievabilities.insert(deck.clone(), Cards::get_deck(deck.clone()).iter().filter_map(|card| card.5["retrievability"].as_f64()).collect());
+        let mut statement = connection.prepare(format!("SELECT STATE, SUSPENDED, COUNT(*) AS COUNT FROM \"{}\" GROUP BY STATE, SUSPENDED", deck.replace(r#"""#, r#""""#))).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            let state = match (statement.read::<i64, _>("SUSPENDED").unwrap(), statement.read::<i64, _>("STATE").unwrap()) {
+                (0, 1) => "new",
+                (0, 2) => "learning",
+                (0, 3) => "review",
+                (0, _) => "relearning",
+                _ => "suspended"
+            };
+            *counts.get_mut(state).unwrap() += statement.read::<i64, _>("COUNT").unwrap();
+        }
+    }
+
+    let all: Vec<f64> = retrievabilities.values().flatten().copied().collect();
+    let mut retrievability = summarize(&all);
+    retrievability["decks"] = retrievabilities.iter().map(|(deck, values)| (deck.clone(), summarize(values))).collect();
+    let rate = |(passed, total): (i64, i64)| json!({"passed": passed, "total": total, "rate": if total == 0 { None } else { Some(passed as f64 / total as f64) }});
+    Ok(json!({
+        "reviews": days.values().map(|day| day.again + day.hard + day.good + day.easy).sum::<i64>(),
+        "learned": days.values().map(|day| day.learned).sum::<i64>(),
+        "time": days.values().map(|day| day.time).sum::<i64>(),
+        "days": days.into_values().collect::<Vec<Day>>(),
+        "retention": {"young": rate(retention[0]), "mature": rate(retention[1])},
+        "counts": counts,
+        "retrievability": retrievability
+    }))
+}
+
+/// The `average` of the retrievabilities of some cards, or null if there are none, and their `distribution` as the number
+/// of cards in each tenth from 0 to 1.
+fn summarize(retrievabilities: &[f64]) -> Value {
+    let mut distribution = [0; 10];
+    for retrievability in retrievabilities { distribution[((retrievability * 10.0) as usize).min(9)] += 1; }
+    json!({
+        "average": if retrievabilities.is_empty() { None } else { Some(retrievabilities.iter().sum::<f64>() / retrievabilities.len() as f64) },
+        "distribution": distribution
+    })
+}
+
+/// The number of cards due on each of the next `options.days` days, starting with today, in every deck or in one, taken from
+/// when the cards are due. Overdue cards are due today, suspended cards are left out, and buried cards are due when they're
+/// unburied. With `options.new`, the new cards each deck's daily limit would introduce are counted too.
+///
+/// The result has the days of each deck in `decks`, keyed by the deck's name, and their sum in `total`. Each day has its
+/// `date` and the number of `review`, `learning` and `new` cards due, along with their `total`.
+pub fn forecast(options: ForecastOptions) -> Result<Value, String> {
+    if options.days < 1 { return Err("The forecast needs at least one day".to_string()); }
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let decks = match options.deck {
+        Some(deck) if Cards::list_decks(&connection).contains(&deck) => vec![deck],
+        Some(deck) => return Err(format!("There is no deck named {}", deck)),
+        None => Cards::list_decks(&connection)
+    };
+    let start = Cards::day_start();
+    let boundary = Cards::day_boundary();
+    let empty: Vec<Due> = (0..options.days).map(|day| Due { date: Cards::day(start + Duration::days(day), boundary).to_string(), ..Due::default() }).collect();
+    let limit = crate::settings::Settings::get_from_file("new_cards").as_i64().unwrap();
+
+    let mut total = empty.clone();
+    let mut forecasts: BTreeMap<String, Vec<Due>> = BTreeMap::new();
+    for deck in decks {
+        let mut days = empty.clone();
+        let (mut new, mut studied_today) = (0, 0);
+        let mut statement = connection.prepare(format!("SELECT DUE, STATE, BURIED_UNTIL, FIRST_STUDY FROM \"{}\" WHERE SUSPENDED = 0", deck.replace(r#"""#, r#""""#))).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            let first_study = statement.read::<Option<String>, _>("FIRST_STUDY").unwrap();
+            if first_study.and_then(|first_study| DateTime::parse_from_rfc3339(&first_study).ok()).is_some_and(|first_study| first_study > start) { studied_today += 1; }
+            let state = statement.read::<i64, _>("STATE").unwrap();
+            if state == 1 {
+                new += 1;
+                continue;
+            }
+
+            let mut due = DateTime::parse_from_rfc3339(&statement.read::<String, _>("DUE").unwrap()).map_err(|error| error.to_string())?.with_timezone(&Utc);
+            if let Some(buried_until) = statement.read::<Option<String>, _>("BURIED_UNTIL").unwrap() {
+                due = due.max(DateTime::parse_from_rfc3339(&buried_until).map_err(|error| error.to_string())?.with_timezone(&Utc));
+            }
+            let Some(day) = days.get_mut((due - start).num_days().max(0) as usize) else { continue; };
+            if state == 3 { day.review += 1; } else { day.learning += 1; }
+        }
+
+        if options.new {
+            // Today's limit is shared with the new cards already studied today.
+            for (index, day) in days.iter_mut().enumerate() {
+                day.new = new.min(if index == 0 { (limit - studied_today).max(0) } else { limit.max(0) });
+                new -= day.new;
+            }
+        }
+        for (day, sum) in days.iter_mut().zip(total.iter_mut()) {
+            day.total = day.review + day.learning + day.new;
+            sum.review += day.review;
+            sum.learning += day.learning;
+            sum.new += day.new;
+            sum.total += day.total;
+        }
+        forecasts.insert(deck, days);
+    }
+
+    Ok(json!({"decks": forecasts, "total": total}))
+}
+
+/// Daily review activity over the past year, in every deck or in one, for a calendar heatmap, with days starting at the hour
+/// set by `day_boundary`.
+///
+/// The result has every day of the year up to today in `days`, with its `date`, its number of `reviews`, and whether it was
+/// `finished`, meaning no card was left due at its end. That is worked out from the review history, so days before the first
+/// review aren't finished, and suspended cards are left out. `streak` is the number of days in a row with reviews up to
+/// today, or up to yesterday if there are none yet today, and `longest_streak` is the longest ever.
+pub fn activity(deck: Option<String>) -> Result<Value, String> {
+    let connection = Connection::open(Cards::get_path()).unwrap();
+    let card_decks = reviews::card_decks(&connection, deck)?;
+    let boundary = Cards::day_boundary();
+    let today = Cards::day(Utc::now(), boundary);
+    let first = today - Duration::days(ACTIVITY_DAYS - 1);
+
+    let mut due: HashMap<String, DateTime<Utc>> = HashMap::new();
+    let mut suspended: HashSet<String> = HashSet::new();
+    for deck in card_decks.values().collect::<HashSet<&String>>() {
+        let mut statement = connection.prepare(format!("SELECT GUID, DUE, STATE, SUSPENDED FROM \"{}\" WHERE GUID IS NOT NULL", deck.replace(r#"""#, r#""""#))).unwrap();
+        while let Ok(DBState::Row) = statement.next() {
+            let guid = statement.read::<String, _>("GUID").unwrap();
+            if statement.read::<i64, _>("SUSPENDED").unwrap() != 0 { suspended.insert(guid); }
+            else if statement.read::<i64, _>("STATE").unwrap() != 1 {
+                due.insert(guid, DateTime::parse_from_rfc3339(&statement.read::<String, _>("DUE").unwrap()).map_err(|error| error.to_string())?.with_timezone(&Utc));
+            }
+        }
+    }
+
+    let mut counts: BTreeMap<NaiveDate, i64> = BTreeMap::new();
+    // Days on which a card was left due, from the day it became due to the day before it was next reviewed.
+    let mut unfinished: HashSet<NaiveDate> = HashSet::new();
+    let mut leave_due = |from: NaiveDate, until: NaiveDate| {
+        for day in from.max(first).iter_days().take_while(|day| *day < until) { unfinished.insert(day); }
+    };
+    let mut next_due: HashMap<String, NaiveDate> = HashMap::new();
+    let mut statement = connection.prepare(format!("SELECT CARD, REVIEWED, SCHEDULED_DAYS FROM {} ORDER BY REVIEWED", reviews::REVIEWS)).unwrap();
+    while let Ok(DBState::Row) = statement.next() {
+        let guid = statement.read::<String, _>("CARD").unwrap();
+        if !card_decks.contains_key(&guid) { continue; }
+        let reviewed = DateTime::parse_from_rfc3339(&statement.read::<String, _>("REVIEWED").unwrap()).map_err(|error| error.to_string())?.with_timezone(&Utc);
+        let day = Cards::day(reviewed, boundary);
+        *counts.entry(day).or_default() += 1;
+        if suspended.contains(&guid) { continue; }
+        let due = Cards::day(reviewed + Duration::days(statement.read::<i64, _>("SCHEDULED_DAYS").unwrap()), boundary);
+        if let Some(previous) = next_due.insert(guid, due) { leave_due(previous, day); }
+    }
+    // Cards that are due now are still due at the end of today.
+    for due in due.values().filter(|due| **due <= Utc::now()) { leave_due(Cards::day(*due, boundary), today + Duration::days(1)); }
+
+    let started = counts.keys().next().copied();
+    let days: Vec<Value> = first.iter_days().take(ACTIVITY_DAYS as usize).map(|day| json!({
+        "date": day.to_string(),
+        "reviews": counts.get(&day).copied().unwrap_or_default(),
+        "finished": started.is_some_and(|started| day >= started) && !unfinished.contains(&day)
+    })).collect();
+
+    let mut streak = 0;
+    let mut day = if counts.contains_key(&today) { today } else { today - Duration::days(1) };
+    while counts.contains_key(&day) {
+        streak += 1;
+        day -= Duration::days(1);
+    }
+    let (mut longest_streak, mut current, mut previous): (i64, i64, Option<NaiveDate>) = (0, 0, None);
+    for day in counts.keys() {
+        current = if previous.is_some_and(|previous| *day - previous == Duration::days(1)) { current + 1 } else { 1 };
+        longest_streak = longest_streak.max(current);
+        previous = Some(*day);
+    }
+
+    Ok(json!({"days": days, "streak": streak, "longest_streak": longest_streak}))
+}
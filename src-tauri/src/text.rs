@@ -0,0 +1,37 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use std::fs;
+use crate::cards::Cards;
+
+/// Writes a deck as a Markdown document titled with the deck's name, with a numbered section for each card holding its
+/// obverse and, after a rule, its reverse and tags.
+pub fn markdown(deck: &str, path: &str) -> Result<(), String> {
+    let mut document = format!("# {}\n", deck);
+    for (number, (_, _, obverse, reverse, _, properties)) in Cards::get_deck(deck.to_string()).into_iter().enumerate() {
+        document.push_str(&format!("\n## {}\n\n{}\n\n---\n\n{}\n", number + 1, obverse.trim(), reverse.trim()));
+        let tags: Vec<&str> = properties["tags"].as_array().unwrap().iter().map(|tag| tag.as_str().unwrap()).collect();
+        if !tags.is_empty() { document.push_str(&format!("\nTags: {}\n", tags.join(" "))); }
+    }
+
+    fs::write(path, document).map_err(|error| error.to_string())
+}
+
+/// Writes a deck as lines of `obverse<TAB>reverse`, which can be imported again as a TSV file. Line breaks and tabs in a
+/// card become spaces, since they would split it.
+pub fn plain(deck: &str, path: &str) -> Result<(), String> {
+    let lines: Vec<String> = Cards::get_deck(deck.to_string()).into_iter().map(|(_, _, obverse, reverse, _, _)| {
+        let flatten = |text: &str| text.split_whitespace().collect::<Vec<&str>>().join(" ");
+        format!("{}\t{}\n", flatten(&obverse), flatten(&reverse))
+    }).collect();
+
+    fs::write(path, lines.concat()).map_err(|error| error.to_string())
+}
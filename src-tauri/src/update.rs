@@ -0,0 +1,139 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+use fsrs::Card;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlite::{Connection, State as DBState};
+use std::collections::{HashMap, HashSet};
+use crate::cards::Cards;
+use crate::{deck_file, notes};
+
+/// How much of an answer has to change, as the share of its characters, for the change to count as substantial.
+const SUBSTANTIAL: f64 = 0.5;
+
+/// How `update` applies a new version of a deck.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct UpdateOptions {
+    /// Whether cards whose answer changed substantially start over as new cards.
+    pub reset: bool,
+    /// Whether cards that aren't in the new version are deleted, along with their progress.
+    pub remove: bool,
+    /// Whether to only work out what would change.
+    pub dry_run: bool
+}
+
+/// Updates a deck to a new version of it in the srs deck format, returning what changed.
+///
+/// Cards are matched by their GUIDs, which stay the same across versions of a shared deck. Matched cards get the new
+/// version's content while keeping their progress, unless `options.reset` is set and their answer changed substantially.
+/// Matched cards whose note type changed in the new version are left as they are. Cards only in the new version are
+/// added, and cards only in the deck are deleted if `options.remove` is set. The deck's metadata, including its version, is
+/// replaced with the new version's.
+///
+/// The result has the deck's `version` before and after, the `added` cards, the `changed` cards with their sides `before`
+/// and `after` and whether the change was `substantial`, the `mismatched` cards whose note type differs, and the `removed`
+/// cards.
+pub fn update(connection: &Connection, deck: &str, file: &Value, options: &UpdateOptions) -> Result<Value, Vec<String>> {
+    let cards = file["cards"].as_array().ok_or(vec!["The deck has no cards".to_string()])?;
+    // The note type of each of the file's notes, compared the way `notes::find_note_type` matches them on import.
+    let types: HashMap<i64, &Value> = file["note_types"].as_array().into_iter().flatten()
+        .filter_map(|note_type| Some((note_type["id"].as_i64()?, note_type)))
+        .collect();
+    let note_types: HashMap<i64, &Value> = file["notes"].as_array().into_iter().flatten()
+        .filter_map(|note| Some((note["id"].as_i64()?, *types.get(&note["note_type"].as_i64()?)?)))
+        .collect();
+    let mut added: Vec<Value> = Vec::new();
+    let mut changed: Vec<Value> = Vec::new();
+    let mut mismatched: Vec<Value> = Vec::new();
+    // The notes whose cards start over.
+    let mut reset: HashSet<i64> = HashSet::new();
+    let mut kept: HashSet<(i64, i64)> = HashSet::new();
+    for card in cards {
+        let (obverse, reverse) = (card["obverse"].as_str().unwrap_or_default(), card["reverse"].as_str().unwrap_or_default());
+        let Some(id) = card["guid"].as_str().and_then(|guid| Cards::deck_guid(connection, deck, guid)) else {
+            added.push(json!({"guid": card["guid"], "obverse": obverse, "reverse": reverse}));
+            continue;
+        };
+
+        let mut statement = connection.prepare(format!("SELECT OBVERSE, REVERSE, NOTE, TEMPLATE FROM \"{}\" WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        statement.next().unwrap();
+        let (old_obverse, old_reverse) = (statement.read::<String, _>("OBVERSE").unwrap(), statement.read::<String, _>("REVERSE").unwrap());
+        let note = statement.read::<i64, _>("NOTE").unwrap();
+        kept.insert((note, statement.read::<i64, _>("TEMPLATE").unwrap()));
+        let (_, _, fields, templates, cloze) = notes::get_note_type(connection, notes::card_note(connection, deck, id).1);
+        let same_type = note_types.get(&card["note"].as_i64().unwrap_or_default()).is_some_and(|note_type| {
+            note_type["fields"] == json!(fields) && note_type["templates"] == json!(templates) && note_type["cloze"].as_bool().unwrap_or(false) == cloze
+        });
+        if !same_type {
+            mismatched.push(json!({"id": id, "guid": card["guid"], "obverse": old_obverse, "reverse": old_reverse}));
+            continue;
+        }
+        if old_obverse == obverse && old_reverse == reverse { continue; }
+        let substantial = difference(&old_reverse, reverse) >= SUBSTANTIAL;
+        if substantial { reset.insert(note); }
+        changed.push(json!({"id": id, "guid": card["guid"], "before": [old_obverse, old_reverse], "after": [obverse, reverse], "substantial": substantial}));
+    }
+
+    // Reversed siblings aren't in the file if the deck's author didn't enable them, but stay as long as their sibling does.
+    let mut removed: Vec<Value> = Vec::new();
+    let mut statement = connection.prepare(format!("SELECT ROWID, GUID, OBVERSE, REVERSE, NOTE, TEMPLATE FROM \"{}\"", deck.replace(r#"""#, r#""""#))).unwrap();
+    while let Ok(DBState::Row) = statement.next() {
+        if kept.contains(&(statement.read::<i64, _>("NOTE").unwrap(), statement.read::<i64, _>("TEMPLATE").unwrap())) { continue; }
+        removed.push(json!({
+            "id": statement.read::<i64, _>("rowid").unwrap(),
+            "guid": statement.read::<String, _>("GUID").unwrap(),
+            "obverse": statement.read::<String, _>("OBVERSE").unwrap(),
+            "reverse": statement.read::<String, _>("REVERSE").unwrap()
+        }));
+    }
+
+    let version = Cards::deck_metadata(connection, deck)["version"].clone();
+    deck_file::import_value(connection, file, Some(deck), options.remove).map_err(|error| vec![error])?;
+    if options.reset {
+        // Siblings like reversed cards are made from the same content, so they start over too.
+        for (deck, id, _, _) in reset.into_iter().flat_map(|note| notes::note_cards(connection, note)) {
+            Cards::write_card(connection, &deck, id, &Card::new());
+            connection.execute(format!("UPDATE \"{}\" SET FIRST_STUDY = NULL WHERE ROWID = {}", deck.replace(r#"""#, r#""""#), id)).unwrap();
+        }
+    }
+    Cards::write_deck_metadata(connection, deck, &file["metadata"]);
+
+    Ok(json!({
+        "version": [version, Cards::deck_metadata(connection, deck)["version"]],
+        "added": added,
+        "changed": changed,
+        "mismatched": mismatched,
+        "removed": removed
+    }))
+}
+
+/// The share of characters that differ between two answers, ignoring formatting, from 0 for the same answer to 1 for a
+/// completely different one.
+fn difference(before: &str, after: &str) -> f64 {
+    let (before, after): (Vec<char>, Vec<char>) = (Cards::normalize(before).chars().collect(), Cards::normalize(after).chars().collect());
+    if before.is_empty() && after.is_empty() { return 0.0; }
+
+    // The Levenshtein distance, keeping one row of the table at a time.
+    let mut row: Vec<usize> = (0..=after.len()).collect();
+    for (i, a) in before.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in after.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + (a != b) as usize);
+            diagonal = above;
+        }
+    }
+
+    row[after.len()] as f64 / before.len().max(after.len()) as f64
+}
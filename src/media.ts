@@ -0,0 +1,30 @@
+/*
+(c) Matthew Boyer, 2023.
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+This Source Code Form is "Incompatible With Secondary Licenses", as
+defined by the Mozilla Public License, v. 2.0.
+*/
+
+import {convertFileSrc} from "@tauri-apps/api/tauri";
+
+const audio = /\.(mp3|ogg|oga|opus|wav|m4a|aac|flac)$/i
+
+// Points images without a URL scheme at the media folder, and shows images of sounds as audio players.
+export default function rehypeMedia() {
+    const visit = (node) => {
+        if (node.type === "element" && node.tagName === "img" && typeof node.properties.src === "string" && !/^[a-z][a-z0-9+.-]*:/i.test(node.properties.src)) {
+            const name = decodeURIComponent(node.properties.src)
+            node.properties.src = convertFileSrc(name, "media")
+            if (audio.test(name)) {
+                node.tagName = "audio"
+                node.properties.controls = true
+            }
+        }
+        node.children?.forEach(visit)
+    }
+    return (tree) => visit(tree)
+}